@@ -9,11 +9,12 @@ CFLAGS="-std=c11 -O2 -g3 -Wall -Wextra -ffreestanding -nostdlib"
 
 # シェルをビルド
 $CC $CFLAGS -Wl,-Tsrc/user/user.ld -Wl,-Map=src/user/shell.map -o src/user/shell.elf src/user/shell.c src/user/user.c src/user/common.c
+mkdir -p ./disk/bin ./disk/etc ./disk/home
 cp src/user/shell.elf ./disk/shell.elf
 
-# tarファイルを作成
+# tarファイルを作成 (disk/ 以下のディレクトリ構成をそのまま使う)
 echo 'hello hello hello' > ./disk/hello.txt
-find ./disk/ -type f | tar --xform='s/.*\///g' -cf disk.tar --format=ustar --files-from=/dev/stdin
+(cd ./disk && tar -cf ../disk.tar *)
 
 # カーネルをビルド＆QEMU起動
 cargo run
@@ -0,0 +1,288 @@
+use crate::{
+    block::{BlockDevice, SECTOR_SIZE},
+    debug, process, warn,
+};
+use core::{ops::Range, ptr};
+
+/// キャッシュできるセクタ数
+const BCACHE_SIZE: usize = 64;
+
+/// 1セクタ分のキャッシュ
+struct Buf {
+    dev: Option<*mut dyn BlockDevice>,
+    sector: u64,
+    dirty: bool,    // ディスクに書き戻していない変更があるか
+    busy: bool,     // 読み書き中でほかのプロセスが触れないか
+    last_used: u64, // LRUで追い出すための最終使用時刻 (`TICK` の値)
+    /// 追い出すために書き戻している以前のセクタ (書き戻しが終わるまではこのセクタとしても見つかる)
+    evicting: Option<(*mut dyn BlockDevice, u64)>,
+    data: [u8; SECTOR_SIZE as usize],
+}
+
+impl Buf {
+    const fn new() -> Self {
+        Self {
+            dev: None,
+            sector: 0,
+            dirty: false,
+            busy: false,
+            last_used: 0,
+            evicting: None,
+            data: [0; SECTOR_SIZE as usize],
+        }
+    }
+
+    fn is(&self, dev: *mut dyn BlockDevice, sector: u64) -> bool {
+        self.in_range(dev, &(sector..(sector + 1)))
+    }
+
+    /// `dev` の `sectors` の範囲のセクタを持っているか (追い出し中の以前のセクタも含む)
+    fn in_range(&self, dev: *mut dyn BlockDevice, sectors: &Range<u64>) -> bool {
+        let matches = |d, s| same_dev(d, dev) && sectors.contains(&s);
+        self.dev.is_some_and(|d| matches(d, self.sector))
+            || self.evicting.is_some_and(|(d, s)| matches(d, s))
+    }
+
+    fn chan(&self) -> u64 {
+        self as *const Buf as u64
+    }
+
+    /// 変更があればディスクに書き戻す (追い出し中なら以前のセクタに書く)
+    unsafe fn write_back(&mut self) -> Result<(), ()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let (dev, sector) = match self.evicting {
+            Some(key) => key,
+            None => (self.dev.unwrap(), self.sector),
+        };
+        if (*dev).write(sector, &self.data).is_err() {
+            warn!("bcache: failed to write sector {sector}");
+            return Err(());
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    unsafe fn unlock(&mut self) {
+        self.busy = false;
+        process::wakeup(self.chan());
+    }
+}
+
+static mut BUFS: [Buf; BCACHE_SIZE] = [const { Buf::new() }; BCACHE_SIZE];
+static mut TICK: u64 = 0;
+
+/// 同じデバイスか (vtableは比べずにオブジェクトのアドレスだけで比べる)
+fn same_dev(a: *mut dyn BlockDevice, b: *mut dyn BlockDevice) -> bool {
+    a as *mut u8 == b as *mut u8
+}
+
+/// `dev` の `sector` のキャッシュを取り出して、ほかのプロセスが触れないようにする
+///
+/// 使い終わったら `Buf::unlock` を呼ぶこと
+unsafe fn get(dev: *mut dyn BlockDevice, sector: u64) -> Result<&'static mut Buf, ()> {
+    TICK += 1;
+
+    loop {
+        let bufs = &*ptr::addr_of!(BUFS);
+        // キャッシュ済みならそれを使う
+        if let Some(i) = bufs.iter().position(|buf| buf.is(dev, sector)) {
+            let buf = &mut *ptr::addr_of_mut!(BUFS[i]);
+            if buf.busy {
+                wait(buf);
+                continue;
+            }
+            buf.busy = true;
+            buf.last_used = TICK;
+            return Ok(buf);
+        }
+
+        // 最も長い間使われていないセクタを追い出す
+        let victim = (0..BCACHE_SIZE)
+            .filter(|i| !bufs[*i].busy)
+            .min_by_key(|i| (bufs[*i].dev.is_some(), bufs[*i].last_used));
+        let buf = match victim {
+            Some(i) => &mut *ptr::addr_of_mut!(BUFS[i]),
+            None => {
+                warn!("bcache: no free buffers");
+                return Err(());
+            }
+        };
+
+        // 書き戻しや読み込みで待っている間にほかのプロセスが同じセクタを別のバッファに
+        // 読まないように、先に新しいキーを設定する。以前のセクタも書き戻しが終わるまでは
+        // このバッファで見つかるので、古い内容をディスクから読まれることもない
+        buf.busy = true;
+        if buf.dirty {
+            buf.evicting = Some((buf.dev.unwrap(), buf.sector));
+        }
+        buf.dev = Some(dev);
+        buf.sector = sector;
+        buf.last_used = TICK;
+        let result = buf.write_back();
+        let evicted = buf.evicting.take();
+        if result.is_err() {
+            // 書き戻せなかった変更を失わないように以前のセクタに戻す
+            if let Some((d, s)) = evicted {
+                buf.dev = Some(d);
+                buf.sector = s;
+            }
+            buf.unlock();
+            return Err(());
+        }
+
+        if (*dev).read(sector, &mut buf.data).is_err() {
+            warn!("bcache: failed to read sector {sector}");
+            buf.dev = None;
+            buf.unlock();
+            return Err(());
+        }
+        return Ok(buf);
+    }
+}
+
+unsafe fn wait(buf: &Buf) {
+    if !process::can_block() {
+        panic!("bcache: buffer is busy");
+    }
+    process::block(buf.chan());
+}
+
+/// `dev` の `offset` バイト目から `buf` の長さ分をキャッシュ経由で読む
+pub unsafe fn read(dev: *mut dyn BlockDevice, offset: u64, buf: &mut [u8]) -> Result<(), ()> {
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = offset + done as u64;
+        let off = (pos % SECTOR_SIZE as u64) as usize;
+        let len = (SECTOR_SIZE as usize - off).min(buf.len() - done);
+
+        let b = get(dev, pos / SECTOR_SIZE as u64)?;
+        buf[done..(done + len)].copy_from_slice(&b.data[off..(off + len)]);
+        b.unlock();
+        done += len;
+    }
+    Ok(())
+}
+
+/// `dev` の `offset` バイト目から `data` をキャッシュに書き込む
+///
+/// 内容が変わったセクタだけを `sync` で書き戻す
+pub unsafe fn write(dev: *mut dyn BlockDevice, offset: u64, data: &[u8]) -> Result<(), ()> {
+    if (*dev).is_read_only() {
+        return Err(());
+    }
+
+    let mut done = 0;
+    while done < data.len() {
+        let pos = offset + done as u64;
+        let off = (pos % SECTOR_SIZE as u64) as usize;
+        let len = (SECTOR_SIZE as usize - off).min(data.len() - done);
+
+        let b = get(dev, pos / SECTOR_SIZE as u64)?;
+        let src = &data[done..(done + len)];
+        if b.data[off..(off + len)] != *src {
+            b.data[off..(off + len)].copy_from_slice(src);
+            b.dirty = true;
+        }
+        b.unlock();
+        done += len;
+    }
+    Ok(())
+}
+
+/// `dev` の `sectors` の範囲のキャッシュを `f` で書き換える (使用中のものは空くまで待つ)
+unsafe fn update_range(dev: *mut dyn BlockDevice, sectors: &Range<u64>, f: fn(&mut Buf)) {
+    for i in 0..BCACHE_SIZE {
+        loop {
+            let buf = &mut *ptr::addr_of_mut!(BUFS[i]);
+            if !buf.in_range(dev, sectors) {
+                break;
+            }
+            if buf.busy {
+                wait(buf);
+                continue;
+            }
+            f(buf);
+            break;
+        }
+    }
+}
+
+/// `dev` の `sectors` を使わなくなったことをデバイスに伝える
+///
+/// 内容は不定になるので、書き戻していない変更も含めてキャッシュから捨てる
+pub unsafe fn discard(dev: *mut dyn BlockDevice, sectors: Range<u64>) -> Result<(), ()> {
+    if (*dev).is_read_only() {
+        return Err(());
+    }
+    update_range(dev, &sectors, |buf| {
+        buf.dev = None;
+        buf.dirty = false;
+    });
+    (*dev).discard(sectors.start, sectors.end - sectors.start)
+}
+
+/// `dev` の `sectors` を0で埋める
+///
+/// キャッシュ済みのセクタはキャッシュ上で0にし、残りはデバイスに直接0を書き込ませる。
+/// デバイスが対応していなければキャッシュ経由で書き込む
+pub unsafe fn write_zeroes(dev: *mut dyn BlockDevice, sectors: Range<u64>) -> Result<(), ()> {
+    if (*dev).is_read_only() {
+        return Err(());
+    }
+    // デバイスが書き込んでいる間に古い内容が書き戻されないように、先にキャッシュを0にする
+    update_range(dev, &sectors, |buf| {
+        if buf.data.iter().any(|b| *b != 0) {
+            buf.data.fill(0);
+            buf.dirty = true;
+        }
+    });
+    if (*dev)
+        .write_zeroes(sectors.start, sectors.end - sectors.start, false)
+        .is_ok()
+    {
+        return Ok(());
+    }
+    let zero = [0; SECTOR_SIZE as usize];
+    for sector in sectors {
+        write(dev, sector * SECTOR_SIZE as u64, &zero)?;
+    }
+    Ok(())
+}
+
+/// `dev` の変更されたセクタをすべて書き戻し、デバイスのキャッシュも永続化する
+pub unsafe fn sync(dev: *mut dyn BlockDevice) -> Result<(), ()> {
+    write_back(dev, 0..u64::MAX)?;
+    (*dev).flush()
+}
+
+/// `dev` の `sectors` の範囲にある変更されたセクタを書き戻す
+///
+/// デバイスのキャッシュは永続化しないので、必要なら `BlockDevice::flush` を呼ぶこと
+pub unsafe fn write_back(dev: *mut dyn BlockDevice, sectors: Range<u64>) -> Result<(), ()> {
+    let mut written = 0;
+    for i in 0..BCACHE_SIZE {
+        loop {
+            let buf = &mut *ptr::addr_of_mut!(BUFS[i]);
+            if !buf.dirty || !buf.in_range(dev, &sectors) {
+                break;
+            }
+            // 待っている間に書き戻されたり追い出されたりしているかもしれないので確かめ直す
+            if buf.busy {
+                wait(buf);
+                continue;
+            }
+
+            buf.busy = true;
+            let result = buf.write_back();
+            buf.unlock();
+            result?;
+            written += 1;
+            break;
+        }
+    }
+
+    debug!("bcache: wrote {written} sectors");
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use crate::info;
+
+pub const SECTOR_SIZE: u32 = 512;
+const BLOCK_DEVICES_MAX: usize = 16;
+const BLOCK_NAME_MAX: usize = 16;
+
+/// セクタ単位で読み書きできるデバイス
+///
+/// バッファの長さは `SECTOR_SIZE` の倍数でなければならない
+pub trait BlockDevice {
+    /// デバイスのセクタ数
+    fn capacity_sectors(&self) -> u64;
+    /// デバイスの論理ブロックサイズ
+    fn block_size(&self) -> u32;
+    fn is_read_only(&self) -> bool;
+    /// `sector` から `buf` の長さ分を読み込む
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), ()>;
+    /// `sector` から `buf` の内容を書き込む
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), ()>;
+    /// 書き込んだ内容を永続化する
+    fn flush(&mut self) -> Result<(), ()>;
+    /// `sector` から `count` セクタを使わなくなったことを伝える (内容は不定になる)
+    fn discard(&mut self, _sector: u64, _count: u64) -> Result<(), ()> {
+        Err(())
+    }
+    /// `sector` から `count` セクタを0で埋める。`unmap` なら領域を解放してもよい
+    fn write_zeroes(&mut self, _sector: u64, _count: u64, _unmap: bool) -> Result<(), ()> {
+        Err(())
+    }
+}
+
+/// 名前付きで登録されたブロックデバイス
+struct Entry {
+    name: [u8; BLOCK_NAME_MAX],
+    name_len: usize,
+    dev: *mut dyn BlockDevice,
+}
+
+impl Entry {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[0..self.name_len]).unwrap_or("")
+    }
+}
+
+static mut BLOCK_DEVICES: [Option<Entry>; BLOCK_DEVICES_MAX] = [const { None }; BLOCK_DEVICES_MAX];
+
+/// ブロックデバイスを `name` (例: `vda`) という名前で登録する
+pub unsafe fn register(name: &str, dev: &'static mut dyn BlockDevice) -> Result<(), ()> {
+    if name.is_empty() || name.len() > BLOCK_NAME_MAX || lookup(name).is_some() {
+        return Err(());
+    }
+
+    let devices = &mut *core::ptr::addr_of_mut!(BLOCK_DEVICES);
+    let slot = devices.iter_mut().find(|slot| slot.is_none()).ok_or(())?;
+    let mut entry = Entry {
+        name: [0; BLOCK_NAME_MAX],
+        name_len: name.len(),
+        dev,
+    };
+    entry.name[0..name.len()].copy_from_slice(name.as_bytes());
+
+    let dev = &*entry.dev;
+    info!(
+        "block: {name}: {} sectors{}",
+        dev.capacity_sectors(),
+        if dev.is_read_only() {
+            ", read-only"
+        } else {
+            ""
+        }
+    );
+    *slot = Some(entry);
+    Ok(())
+}
+
+/// `name` という名前のブロックデバイスを探す
+pub unsafe fn lookup(name: &str) -> Option<&'static mut dyn BlockDevice> {
+    let devices = &*core::ptr::addr_of!(BLOCK_DEVICES);
+    devices
+        .iter()
+        .flatten()
+        .find(|entry| entry.name() == name)
+        .map(|entry| &mut *entry.dev)
+}
+
+/// 登録されているブロックデバイスの数
+pub unsafe fn count() -> usize {
+    let devices = &*core::ptr::addr_of!(BLOCK_DEVICES);
+    devices.iter().flatten().count()
+}
+
+/// 登録順で `index` 番目のブロックデバイスとその名前
+pub unsafe fn get(index: usize) -> Option<(&'static str, &'static mut dyn BlockDevice)> {
+    let devices = &*core::ptr::addr_of!(BLOCK_DEVICES);
+    let entry = devices.get(index)?.as_ref()?;
+    Some((entry.name(), &mut *entry.dev))
+}
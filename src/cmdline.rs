@@ -0,0 +1,105 @@
+use crate::{
+    fdt::PLATFORM,
+    print::{LOG_DEBUG, LOG_LEVEL},
+    warn,
+};
+
+const INIT_MAX: usize = 100;
+const DEFAULT_INIT: &[u8] = b"shell.elf";
+const ROOT_MAX: usize = 16;
+#[cfg(not(feature = "ramdisk"))]
+const DEFAULT_ROOT: &[u8] = b"vda";
+/// 埋め込んだイメージから起動する場合は `-drive` がなくても動くようにする
+#[cfg(feature = "ramdisk")]
+const DEFAULT_ROOT: &[u8] = b"ram0";
+/// スケジューラのタイムスライス (ミリ秒)
+const DEFAULT_QUANTUM_MS: u64 = 10;
+
+/// `/chosen/bootargs` から読み取ったカーネルのオプション
+#[derive(Debug)]
+pub struct Cmdline {
+    init: [u8; INIT_MAX],
+    init_len: usize,
+    root: [u8; ROOT_MAX],
+    root_len: usize,
+    pub quantum_ms: u64,
+}
+
+impl Cmdline {
+    const fn new() -> Self {
+        Self {
+            init: [0; INIT_MAX],
+            init_len: 0,
+            root: [0; ROOT_MAX],
+            root_len: 0,
+            quantum_ms: DEFAULT_QUANTUM_MS,
+        }
+    }
+
+    /// 最初に起動するプログラムのファイル名
+    pub fn init(&self) -> &str {
+        core::str::from_utf8(&self.init[0..self.init_len]).unwrap_or("")
+    }
+
+    /// ファイルシステムを置くブロックデバイスの名前
+    pub fn root(&self) -> &str {
+        core::str::from_utf8(&self.root[0..self.root_len]).unwrap_or("")
+    }
+}
+
+pub static mut CMDLINE: Cmdline = Cmdline::new();
+
+pub unsafe fn init() {
+    let cmdline = &mut CMDLINE;
+    cmdline.init[0..DEFAULT_INIT.len()].copy_from_slice(DEFAULT_INIT);
+    cmdline.init_len = DEFAULT_INIT.len();
+    cmdline.root[0..DEFAULT_ROOT.len()].copy_from_slice(DEFAULT_ROOT);
+    cmdline.root_len = DEFAULT_ROOT.len();
+
+    let bootargs = &PLATFORM.bootargs[0..PLATFORM.bootargs_len];
+    for arg in bootargs.split(|c| *c == b' ').filter(|arg| !arg.is_empty()) {
+        let (key, value) = match arg.iter().position(|c| *c == b'=') {
+            Some(i) => (&arg[0..i], &arg[(i + 1)..]),
+            None => (arg, &arg[arg.len()..]),
+        };
+
+        match key {
+            b"init" if value.len() > INIT_MAX => warn!("cmdline: init value too long"),
+            b"root" if value.len() > ROOT_MAX => warn!("cmdline: root value too long"),
+            b"init" if !value.is_empty() => {
+                cmdline.init[0..value.len()].copy_from_slice(value);
+                cmdline.init_len = value.len();
+            }
+            b"root" if !value.is_empty() => {
+                cmdline.root[0..value.len()].copy_from_slice(value);
+                cmdline.root_len = value.len();
+            }
+            b"loglevel" => match parse_u64(value) {
+                Some(level) => LOG_LEVEL = level.min(LOG_DEBUG as u64) as u8,
+                None => warn!("cmdline: invalid loglevel"),
+            },
+            b"quantum" => match parse_u64(value) {
+                Some(ms) => cmdline.quantum_ms = ms,
+                None => warn!("cmdline: invalid quantum"),
+            },
+            _ => warn!(
+                "cmdline: unknown option: {}",
+                core::str::from_utf8(arg).unwrap_or("?")
+            ),
+        }
+    }
+}
+
+fn parse_u64(s: &[u8]) -> Option<u64> {
+    if s.is_empty() {
+        return None;
+    }
+    let mut value: u64 = 0;
+    for c in s {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        value = value.checked_mul(10)?.checked_add((c - b'0') as u64)?;
+    }
+    Some(value)
+}
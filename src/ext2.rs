@@ -0,0 +1,530 @@
+use crate::{
+    bcache,
+    block::{BlockDevice, SECTOR_SIZE},
+    info,
+    vfs::{DirEntry, FileSystem, FileType, Ino, Stat, NAME_MAX, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG},
+    warn,
+};
+use core::ptr;
+
+/// 同時にマウントできるext2ボリュームの数
+const VOLUMES_MAX: usize = 4;
+
+/// スーパーブロックはブロックサイズにかかわらず1024バイト目から始まる
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const SB_INODES_COUNT: usize = 0;
+const SB_BLOCKS_COUNT: usize = 4;
+const SB_FIRST_DATA_BLOCK: usize = 20;
+const SB_LOG_BLOCK_SIZE: usize = 24;
+const SB_BLOCKS_PER_GROUP: usize = 32;
+const SB_INODES_PER_GROUP: usize = 40;
+const SB_MAGIC: usize = 56;
+const SB_REV_LEVEL: usize = 76;
+const SB_INODE_SIZE: usize = 88;
+const SB_FEATURE_INCOMPAT: usize = 96;
+const EXT2_MAGIC: u16 = 0xef53;
+/// リビジョン0のinodeの大きさ
+const GOOD_OLD_INODE_SIZE: u32 = 128;
+/// 1024 << 6 = 64KiBより大きいブロックは扱わない
+const LOG_BLOCK_SIZE_MAX: u32 = 6;
+
+/// ディレクトリエントリにファイルの種類が入っている
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// グループディスクリプタやブロックの配置を変えるだけで、読むのに影響しない
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+/// グループディスクリプタ
+const GROUP_DESC_SIZE: u64 = 32;
+const GD_INODE_TABLE: usize = 8;
+
+/// inodeのフィールドの位置
+const INODE_MODE: usize = 0;
+const INODE_UID: usize = 2;
+const INODE_SIZE: usize = 4;
+const INODE_MTIME: usize = 16;
+const INODE_GID: usize = 24;
+const INODE_LINKS_COUNT: usize = 26;
+const INODE_BLOCKS: usize = 28;
+const INODE_BLOCK: usize = 40;
+const INODE_FILE_ACL: usize = 104;
+/// 通常のファイルのサイズの上位32ビット (large_file)
+const INODE_SIZE_HIGH: usize = 108;
+const INODE_UID_HIGH: usize = 120;
+const INODE_GID_HIGH: usize = 122;
+/// 読み込むinodeの大きさ (拡張部分は使わない)
+const INODE_READ_SIZE: usize = 128;
+
+/// `i_block` の直接ブロックと間接ブロックの数
+const DIRECT_BLOCKS: usize = 12;
+const IND_BLOCK: usize = 12;
+const DIND_BLOCK: usize = 13;
+const TIND_BLOCK: usize = 14;
+const BLOCK_POINTERS: usize = 15;
+/// シンボリックリンクの指す先がこれより短ければ `i_block` に直接入っている
+const FAST_SYMLINK_MAX: u64 = 60;
+
+/// ディレクトリエントリ
+const DIRENT_HEADER_SIZE: usize = 8;
+const DIRENT_INODE: usize = 0;
+const DIRENT_REC_LEN: usize = 4;
+const DIRENT_NAME_LEN: usize = 6;
+const DIRENT_FILE_TYPE: usize = 7;
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+const ROOT_INO: Ino = 2;
+
+fn le16(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes(buf[off..(off + 2)].try_into().unwrap())
+}
+
+fn le32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..(off + 4)].try_into().unwrap())
+}
+
+/// 読み込んだinode
+struct Inode {
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    links: u32,
+    size: u64,
+    mtime: u32,
+    /// 512バイト単位で数えた使用ブロック数
+    blocks: u32,
+    file_acl: u32,
+    block: [u32; BLOCK_POINTERS],
+}
+
+impl Inode {
+    fn file_type(&self) -> u32 {
+        self.mode & S_IFMT
+    }
+}
+
+/// 読み込んだディレクトリエントリ
+struct RawDirent {
+    ino: u32,
+    file_type: u8,
+    name: [u8; NAME_MAX],
+    name_len: usize,
+}
+
+impl RawDirent {
+    fn name(&self) -> &[u8] {
+        &self.name[0..self.name_len]
+    }
+}
+
+/// マウントされたext2ボリューム (読み込み専用)
+struct Ext2 {
+    dev: *mut dyn BlockDevice,
+    block_size: u64,
+    inodes_count: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    /// グループディスクリプタテーブルの位置
+    group_desc_off: u64,
+    groups: u32,
+    filetype: bool,
+}
+
+static mut VOLUMES: [Option<Ext2>; VOLUMES_MAX] = [const { None }; VOLUMES_MAX];
+
+/// `dev` のext2ボリュームを読み込む
+pub unsafe fn mount(dev: *mut dyn BlockDevice) -> Result<&'static mut dyn FileSystem, ()> {
+    let volumes = &mut *ptr::addr_of_mut!(VOLUMES);
+    if volumes
+        .iter()
+        .flatten()
+        .any(|v| v.dev as *mut u8 == dev as *mut u8)
+    {
+        warn!("ext2: already mounted");
+        return Err(());
+    }
+    let slot = volumes.iter_mut().find(|slot| slot.is_none()).ok_or(())?;
+
+    if (*dev).capacity_sectors() * (SECTOR_SIZE as u64) < SUPERBLOCK_OFFSET + SUPERBLOCK_SIZE as u64
+    {
+        return Err(());
+    }
+    let mut sb = [0; SUPERBLOCK_SIZE];
+    bcache::read(dev, SUPERBLOCK_OFFSET, &mut sb)?;
+    if le16(&sb, SB_MAGIC) != EXT2_MAGIC {
+        return Err(());
+    }
+
+    let log_block_size = le32(&sb, SB_LOG_BLOCK_SIZE);
+    let blocks_count = le32(&sb, SB_BLOCKS_COUNT) as u64;
+    let first_data_block = le32(&sb, SB_FIRST_DATA_BLOCK) as u64;
+    let blocks_per_group = le32(&sb, SB_BLOCKS_PER_GROUP) as u64;
+    let inodes_count = le32(&sb, SB_INODES_COUNT);
+    let inodes_per_group = le32(&sb, SB_INODES_PER_GROUP);
+    if log_block_size > LOG_BLOCK_SIZE_MAX
+        || blocks_per_group == 0
+        || inodes_per_group == 0
+        || blocks_count <= first_data_block
+    {
+        warn!("ext2: invalid superblock");
+        return Err(());
+    }
+    let block_size = 1024 << log_block_size;
+    if blocks_count * block_size > (*dev).capacity_sectors() * SECTOR_SIZE as u64 {
+        warn!("ext2: filesystem is larger than the device");
+        return Err(());
+    }
+
+    let (inode_size, incompat) = match le32(&sb, SB_REV_LEVEL) {
+        0 => (GOOD_OLD_INODE_SIZE, 0),
+        _ => (
+            le16(&sb, SB_INODE_SIZE) as u32,
+            le32(&sb, SB_FEATURE_INCOMPAT),
+        ),
+    };
+    if (inode_size as usize) < INODE_READ_SIZE || !inode_size.is_power_of_two() {
+        warn!("ext2: unsupported inode size {inode_size}");
+        return Err(());
+    }
+    // ext3のジャーナルの回復やext4のextentなどが必要なら読めない
+    if incompat & !INCOMPAT_SUPPORTED != 0 {
+        warn!(
+            "ext2: unsupported features {:#x}",
+            incompat & !INCOMPAT_SUPPORTED
+        );
+        return Err(());
+    }
+
+    let groups = (blocks_count - first_data_block).div_ceil(blocks_per_group) as u32;
+    let volume = Ext2 {
+        dev,
+        block_size,
+        inodes_count,
+        inodes_per_group,
+        inode_size: inode_size as u64,
+        group_desc_off: (first_data_block + 1) * block_size,
+        groups,
+        filetype: incompat & INCOMPAT_FILETYPE != 0,
+    };
+    if volume.file_type_of(ROOT_INO)? != FileType::Directory {
+        warn!("ext2: root is not a directory");
+        return Err(());
+    }
+
+    info!("ext2: {blocks_count} blocks of {block_size} bytes, {groups} groups");
+    *slot = Some(volume);
+    Ok(slot.as_mut().unwrap())
+}
+
+impl Ext2 {
+    unsafe fn read_inode(&self, ino: Ino) -> Result<Inode, ()> {
+        if ino == 0 || ino > self.inodes_count as u64 {
+            return Err(());
+        }
+        let group = (ino - 1) / self.inodes_per_group as u64;
+        let index = (ino - 1) % self.inodes_per_group as u64;
+        if group >= self.groups as u64 {
+            return Err(());
+        }
+
+        let mut desc = [0; GROUP_DESC_SIZE as usize];
+        bcache::read(
+            self.dev,
+            self.group_desc_off + group * GROUP_DESC_SIZE,
+            &mut desc,
+        )?;
+        let table = le32(&desc, GD_INODE_TABLE) as u64;
+
+        let mut raw = [0; INODE_READ_SIZE];
+        bcache::read(
+            self.dev,
+            table * self.block_size + index * self.inode_size,
+            &mut raw,
+        )?;
+
+        let mode = le16(&raw, INODE_MODE) as u32;
+        let mut size = le32(&raw, INODE_SIZE) as u64;
+        if mode & S_IFMT == S_IFREG {
+            size |= (le32(&raw, INODE_SIZE_HIGH) as u64) << 32;
+        }
+        let mut block = [0; BLOCK_POINTERS];
+        for (i, b) in block.iter_mut().enumerate() {
+            *b = le32(&raw, INODE_BLOCK + i * 4);
+        }
+        Ok(Inode {
+            mode,
+            uid: le16(&raw, INODE_UID) as u32 | (le16(&raw, INODE_UID_HIGH) as u32) << 16,
+            gid: le16(&raw, INODE_GID) as u32 | (le16(&raw, INODE_GID_HIGH) as u32) << 16,
+            links: le16(&raw, INODE_LINKS_COUNT) as u32,
+            size,
+            mtime: le32(&raw, INODE_MTIME),
+            blocks: le32(&raw, INODE_BLOCKS),
+            file_acl: le32(&raw, INODE_FILE_ACL),
+            block,
+        })
+    }
+
+    unsafe fn file_type_of(&self, ino: Ino) -> Result<FileType, ()> {
+        Ok(match self.read_inode(ino)?.file_type() {
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            _ => FileType::Regular,
+        })
+    }
+
+    /// 間接ブロック `block` の `index` 番目のブロック番号
+    unsafe fn indirect(&self, block: u32, index: u64) -> Result<u32, ()> {
+        if block == 0 {
+            return Ok(0);
+        }
+        let mut buf = [0; 4];
+        bcache::read(
+            self.dev,
+            block as u64 * self.block_size + index * 4,
+            &mut buf,
+        )?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// ファイルの `index` 番目のブロックのディスク上のブロック番号。穴なら0
+    unsafe fn map_block(&self, inode: &Inode, index: u64) -> Result<u32, ()> {
+        let per_block = self.block_size / 4;
+        let mut index = index;
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok(inode.block[index as usize]);
+        }
+        index -= DIRECT_BLOCKS as u64;
+        if index < per_block {
+            return self.indirect(inode.block[IND_BLOCK], index);
+        }
+        index -= per_block;
+        if index < per_block * per_block {
+            let ind = self.indirect(inode.block[DIND_BLOCK], index / per_block)?;
+            return self.indirect(ind, index % per_block);
+        }
+        index -= per_block * per_block;
+        if index < per_block * per_block * per_block {
+            let dind = self.indirect(inode.block[TIND_BLOCK], index / (per_block * per_block))?;
+            let ind = self.indirect(dind, index / per_block % per_block)?;
+            return self.indirect(ind, index % per_block);
+        }
+        Err(())
+    }
+
+    /// `inode` のデータを `offset` バイト目から読む (穴は0で埋める)
+    unsafe fn read_data(&self, inode: &Inode, offset: u64, buf: &mut [u8]) -> Result<usize, ()> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = (buf.len() as u64).min(inode.size - offset) as usize;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let inner = pos % self.block_size;
+            let n = ((self.block_size - inner) as usize).min(len - done);
+            let dst = &mut buf[done..(done + n)];
+            match self.map_block(inode, pos / self.block_size)? {
+                0 => dst.fill(0),
+                block => bcache::read(self.dev, block as u64 * self.block_size + inner, dst)?,
+            }
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// ディレクトリの `pos` バイト目から次の使われているエントリを読む
+    unsafe fn next_dirent(&self, dir: &Inode, pos: &mut u64) -> Result<Option<RawDirent>, ()> {
+        while *pos < dir.size {
+            let inner = *pos % self.block_size;
+            let block = self.map_block(dir, *pos / self.block_size)?;
+            if block == 0 {
+                warn!("ext2: hole in directory");
+                return Err(());
+            }
+            let off = block as u64 * self.block_size + inner;
+            let mut header = [0; DIRENT_HEADER_SIZE];
+            bcache::read(self.dev, off, &mut header)?;
+
+            let ino = le32(&header, DIRENT_INODE);
+            let rec_len = le16(&header, DIRENT_REC_LEN) as u64;
+            let name_len = if self.filetype {
+                header[DIRENT_NAME_LEN] as usize
+            } else {
+                le16(&header, DIRENT_NAME_LEN) as usize
+            };
+            // エントリはブロックをまたがない
+            if rec_len < DIRENT_HEADER_SIZE as u64
+                || !rec_len.is_multiple_of(4)
+                || inner + rec_len > self.block_size
+                || (name_len + DIRENT_HEADER_SIZE) as u64 > rec_len
+                || name_len > NAME_MAX
+            {
+                warn!("ext2: broken directory entry at {off}");
+                return Err(());
+            }
+            *pos += rec_len;
+            if ino == 0 {
+                continue;
+            }
+
+            let mut entry = RawDirent {
+                ino,
+                file_type: if self.filetype {
+                    header[DIRENT_FILE_TYPE]
+                } else {
+                    0
+                },
+                name: [0; NAME_MAX],
+                name_len,
+            };
+            bcache::read(
+                self.dev,
+                off + DIRENT_HEADER_SIZE as u64,
+                &mut entry.name[0..name_len],
+            )?;
+            return Ok(Some(entry));
+        }
+        Ok(None)
+    }
+
+    unsafe fn read_dir_inode(&self, ino: Ino) -> Result<Inode, ()> {
+        let inode = self.read_inode(ino)?;
+        if inode.file_type() != S_IFDIR {
+            return Err(());
+        }
+        Ok(inode)
+    }
+}
+
+impl FileSystem for Ext2 {
+    fn root(&self) -> Ino {
+        ROOT_INO
+    }
+
+    fn lookup(&mut self, dir: Ino, name: &[u8]) -> Result<Ino, ()> {
+        unsafe {
+            // `.` と `..` もディレクトリエントリとして入っている
+            let inode = self.read_dir_inode(dir)?;
+            let mut pos = 0;
+            while let Some(entry) = self.next_dirent(&inode, &mut pos)? {
+                if entry.name() == name {
+                    return Ok(entry.ino as Ino);
+                }
+            }
+            Err(())
+        }
+    }
+
+    fn stat(&mut self, ino: Ino) -> Result<Stat, ()> {
+        let inode = unsafe { self.read_inode(ino)? };
+        Ok(Stat {
+            mode: inode.mode,
+            uid: inode.uid,
+            gid: inode.gid,
+            nlink: inode.links,
+            size: inode.size,
+            mtime: inode.mtime as u64,
+        })
+    }
+
+    fn read(&mut self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, ()> {
+        unsafe {
+            let inode = self.read_inode(ino)?;
+            if inode.file_type() != S_IFREG {
+                return Err(());
+            }
+            self.read_data(&inode, offset as u64, buf)
+        }
+    }
+
+    fn write(&mut self, _ino: Ino, _offset: usize, _data: &[u8]) -> Result<usize, ()> {
+        Err(())
+    }
+
+    fn truncate(&mut self, _ino: Ino, _size: usize) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn readlink(&mut self, ino: Ino, buf: &mut [u8]) -> Result<usize, ()> {
+        unsafe {
+            let inode = self.read_inode(ino)?;
+            if inode.file_type() != S_IFLNK || inode.size > buf.len() as u64 {
+                return Err(());
+            }
+            let len = inode.size as usize;
+
+            // 拡張属性のブロックを除いてデータブロックがなければ `i_block` に直接入っている
+            let acl_blocks = if inode.file_acl != 0 {
+                (self.block_size / SECTOR_SIZE as u64) as u32
+            } else {
+                0
+            };
+            if inode.size < FAST_SYMLINK_MAX && inode.blocks == acl_blocks {
+                for (i, c) in buf[0..len].iter_mut().enumerate() {
+                    *c = inode.block[i / 4].to_le_bytes()[i % 4];
+                }
+                return Ok(len);
+            }
+            self.read_data(&inode, 0, &mut buf[0..len])
+        }
+    }
+
+    fn readdir(&mut self, dir: Ino, index: usize) -> Result<Option<DirEntry>, ()> {
+        unsafe {
+            let inode = self.read_dir_inode(dir)?;
+            let mut pos = 0;
+            let mut i = 0;
+            while let Some(entry) = self.next_dirent(&inode, &mut pos)? {
+                if entry.name() == b"." || entry.name() == b".." {
+                    continue;
+                }
+                if i == index {
+                    let kind = match entry.file_type {
+                        FT_REG_FILE => FileType::Regular,
+                        FT_DIR => FileType::Directory,
+                        FT_SYMLINK => FileType::Symlink,
+                        // 種類が入っていなければinodeを読む
+                        0 => self.file_type_of(entry.ino as Ino)?,
+                        _ => FileType::Regular,
+                    };
+                    return DirEntry::new(entry.name(), kind).map(Some);
+                }
+                i += 1;
+            }
+            Ok(None)
+        }
+    }
+
+    // いまのところ読み込み専用
+    fn create(&mut self, _dir: Ino, _name: &[u8], _kind: FileType) -> Result<Ino, ()> {
+        Err(())
+    }
+
+    fn unlink(&mut self, _dir: Ino, _name: &[u8]) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn rmdir(&mut self, _dir: Ino, _name: &[u8]) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn rename(
+        &mut self,
+        _old_dir: Ino,
+        _old_name: &[u8],
+        _new_dir: Ino,
+        _new_name: &[u8],
+    ) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn fsync(&mut self, _ino: Ino) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,1350 @@
+use crate::{
+    bcache,
+    block::{BlockDevice, SECTOR_SIZE},
+    info, rtc,
+    vfs::{DirEntry, FileSystem, FileType, Ino, Stat, NAME_MAX, S_IFDIR, S_IFREG},
+    warn,
+};
+use core::{ops::Range, ptr};
+
+/// 同時にマウントできるFAT32ボリュームの数
+const VOLUMES_MAX: usize = 4;
+
+/// ブートセクタ (BPB) のフィールドの位置
+const BPB_BYTES_PER_SECTOR: usize = 11;
+const BPB_SECTORS_PER_CLUSTER: usize = 13;
+const BPB_RESERVED_SECTORS: usize = 14;
+const BPB_NUM_FATS: usize = 16;
+const BPB_ROOT_ENTRIES: usize = 17;
+const BPB_TOTAL_SECTORS16: usize = 19;
+const BPB_FAT_SIZE16: usize = 22;
+const BPB_TOTAL_SECTORS32: usize = 32;
+const BPB_FAT_SIZE32: usize = 36;
+const BPB_EXT_FLAGS: usize = 40;
+const BPB_ROOT_CLUSTER: usize = 44;
+const BPB_FSINFO_SECTOR: usize = 48;
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// 立っていればFATをミラーせず、下位4ビットのFATだけを使う
+const EXT_FLAGS_NO_MIRROR: u16 = 0x80;
+
+/// FSInfoセクタ
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIG: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIG: u32 = 0xaa55_0000;
+const FSINFO_STRUCT_SIG_OFFSET: usize = 484;
+const FSINFO_FREE_COUNT: usize = 488;
+const FSINFO_NEXT_FREE: usize = 492;
+const FSINFO_TRAIL_SIG_OFFSET: usize = 508;
+/// 空きクラスタ数が分からないときの値
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+/// FATのエントリ (上位4ビットは予約されている)
+const FAT_ENTRY_MASK: u32 = 0x0fff_ffff;
+const FAT_FREE: u32 = 0;
+const FAT_BAD: u32 = 0x0fff_fff7;
+/// これ以上ならチェインの終わり
+const FAT_EOC_MIN: u32 = 0x0fff_fff8;
+const FAT_EOC: u32 = 0x0fff_ffff;
+const FIRST_CLUSTER: u32 = 2;
+
+/// ディレクトリエントリ
+const DIRENT_SIZE: usize = 32;
+const DIRENT_ATTR: usize = 11;
+const DIRENT_CASE: usize = 12;
+const DIRENT_CTIME: usize = 14;
+const DIRENT_CDATE: usize = 16;
+const DIRENT_ADATE: usize = 18;
+const DIRENT_CLUSTER_HI: usize = 20;
+const DIRENT_MTIME: usize = 22;
+const DIRENT_MDATE: usize = 24;
+const DIRENT_CLUSTER_LO: usize = 26;
+const DIRENT_SIZE_OFFSET: usize = 28;
+/// 名前の先頭がこれなら削除されたエントリ、0ならディレクトリの終わり
+const DIRENT_DELETED: u8 = 0xe5;
+const DIRENT_END: u8 = 0x00;
+/// 名前の先頭が本当に0xe5のときに代わりに使う値
+const DIRENT_KANJI_E5: u8 = 0x05;
+const SHORT_NAME_LEN: usize = 11;
+const SHORT_BASE_LEN: usize = 8;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// 長い名前のエントリの属性 (READ_ONLY | HIDDEN | SYSTEM | VOLUME_ID)
+const ATTR_LFN: u8 = 0x0f;
+
+/// 短い名前の本体と拡張子を小文字で表示するフラグ (Windows NTの拡張)
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+/// 長い名前のエントリ
+const LFN_LAST: u8 = 0x40;
+const LFN_ORD_MASK: u8 = 0x1f;
+const LFN_CHECKSUM: usize = 13;
+/// 1エントリに入るUCS-2の文字の位置
+const LFN_CHAR_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LFN_CHARS_PER_SLOT: usize = LFN_CHAR_OFFSETS.len();
+/// 255文字の名前に必要なエントリ数
+const LFN_SLOTS_MAX: usize = 20;
+const LFN_CHARS_MAX: usize = 255;
+
+/// 短い名前の末尾に付ける `~N` の最大値
+const SHORT_TAIL_MAX: u32 = 999_999;
+/// 短い名前に使える記号
+const SHORT_NAME_SYMBOLS: &[u8] = b"!#$%&'()-@^_`{}~";
+/// 長い名前にも使えない文字
+const INVALID_NAME_CHARS: &[u8] = b"\"*/:<>?\\|";
+
+/// FATの日付は1980年から
+const FAT_EPOCH_YEAR: i64 = 1980;
+
+/// ルートディレクトリの番号。ほかのファイルはディレクトリエントリのディスク上の位置を番号にする
+const ROOT_INO: Ino = 0;
+
+static ZERO_SECTOR: [u8; SECTOR_SIZE as usize] = [0; SECTOR_SIZE as usize];
+
+fn le16(buf: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes(buf[off..(off + 2)].try_into().unwrap())
+}
+
+fn le32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..(off + 4)].try_into().unwrap())
+}
+
+fn set_le16(buf: &mut [u8], off: usize, value: u16) {
+    buf[off..(off + 2)].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_le32(buf: &mut [u8], off: usize, value: u32) {
+    buf[off..(off + 4)].copy_from_slice(&value.to_le_bytes());
+}
+
+/// 1970年1月1日からの日数
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// 1970年1月1日からの日数を年月日にする
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// UNIX時間をFATの日付と時刻にする (タイムゾーンはUTCとみなす)
+fn fat_time(secs: u64) -> (u16, u16) {
+    let secs = secs as i64;
+    let (year, month, day) = civil_from_days(secs / 86400);
+    if year < FAT_EPOCH_YEAR {
+        return ((1 << 5) | 1, 0);
+    }
+    let year = (year - FAT_EPOCH_YEAR).min(127);
+    let rem = secs % 86400;
+    let date = (year << 9) | (month << 5) | day;
+    let time = ((rem / 3600) << 11) | ((rem / 60 % 60) << 5) | (rem % 60 / 2);
+    (date as u16, time as u16)
+}
+
+/// FATの日付と時刻をUNIX時間にする
+fn unix_time(date: u16, time: u16) -> u64 {
+    if date == 0 {
+        return 0;
+    }
+    let year = FAT_EPOCH_YEAR + (date >> 9) as i64;
+    let month = ((date >> 5) & 0xf).clamp(1, 12) as i64;
+    let day = (date & 0x1f).max(1) as i64;
+    let secs =
+        ((time >> 11) as i64 * 60 + ((time >> 5) & 0x3f) as i64) * 60 + (time & 0x1f) as i64 * 2;
+    (days_from_civil(year, month, day) * 86400 + secs) as u64
+}
+
+/// 短い名前から計算する、長い名前のエントリに入れるチェックサム
+fn short_name_checksum(short: &[u8]) -> u8 {
+    short[0..SHORT_NAME_LEN]
+        .iter()
+        .fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c))
+}
+
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || SHORT_NAME_SYMBOLS.contains(&c)
+}
+
+/// 名前を本体と拡張子に分ける (先頭のドットは拡張子の区切りとみなさない)
+fn split_ext(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(i) if i > 0 => (&name[0..i], &name[(i + 1)..]),
+        _ => (name, ""),
+    }
+}
+
+/// ファイル名として使えるか
+fn valid_name(name: &[u8]) -> bool {
+    let Ok(name) = core::str::from_utf8(name) else {
+        return false;
+    };
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.ends_with(['.', ' '])
+        && name.encode_utf16().count() <= LFN_CHARS_MAX
+        && !name
+            .bytes()
+            .any(|c| c < 0x20 || INVALID_NAME_CHARS.contains(&c))
+}
+
+/// 名前がそのまま8.3形式で表せれば、短い名前と大文字小文字のフラグを返す
+fn exact_short_name(name: &str) -> Option<([u8; SHORT_NAME_LEN], u8)> {
+    let (base, ext) = split_ext(name);
+    if base.is_empty() || base.len() > SHORT_BASE_LEN || ext.len() > 3 {
+        return None;
+    }
+
+    let mut short = [b' '; SHORT_NAME_LEN];
+    let mut case = 0;
+    for (part, offset, flag) in [
+        (base, 0, CASE_LOWER_BASE),
+        (ext, SHORT_BASE_LEN, CASE_LOWER_EXT),
+    ] {
+        if !part.bytes().all(is_short_name_char) {
+            return None;
+        }
+        // 大文字と小文字が混ざっていると短い名前だけでは表せない
+        let lower = part.bytes().any(|c| c.is_ascii_lowercase());
+        let upper = part.bytes().any(|c| c.is_ascii_uppercase());
+        if lower && upper {
+            return None;
+        }
+        if lower {
+            case |= flag;
+        }
+        short[offset..(offset + part.len())].copy_from_slice(part.as_bytes());
+    }
+    short.make_ascii_uppercase();
+    Some((short, case))
+}
+
+/// 短い名前にできない文字を `_` に置き換えて大文字にする
+fn short_basis(part: &str, buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    for c in part.chars().filter(|c| *c != '.' && *c != ' ') {
+        if len == buf.len() {
+            break;
+        }
+        buf[len] = match u8::try_from(c) {
+            Ok(c) if is_short_name_char(c) => c.to_ascii_uppercase(),
+            _ => b'_',
+        };
+        len += 1;
+    }
+    len
+}
+
+/// ディレクトリの中の位置
+#[derive(Clone, Copy)]
+struct Cursor {
+    cluster: u32,
+    /// クラスタの中でのバイト単位の位置
+    pos: u64,
+    /// 先頭から進んだクラスタ数
+    steps: u32,
+}
+
+impl Cursor {
+    fn new(cluster: u32) -> Self {
+        Self {
+            cluster,
+            pos: 0,
+            steps: 0,
+        }
+    }
+}
+
+/// 読み込んだディレクトリエントリ
+struct Entry {
+    /// 最初のエントリ (長い名前があればその先頭) の位置
+    start: Cursor,
+    /// 長い名前のエントリを含めたエントリ数
+    slots: usize,
+    /// 短い名前のエントリのディスク上の位置
+    off: u64,
+    raw: [u8; DIRENT_SIZE],
+    name: [u8; NAME_MAX],
+    name_len: usize,
+}
+
+impl Entry {
+    fn name(&self) -> &[u8] {
+        &self.name[0..self.name_len]
+    }
+
+    fn is_dir(&self) -> bool {
+        self.raw[DIRENT_ATTR] & ATTR_DIRECTORY != 0
+    }
+
+    fn is_dot(&self) -> bool {
+        self.name() == b"." || self.name() == b".."
+    }
+
+    fn cluster(&self) -> u32 {
+        raw_cluster(&self.raw)
+    }
+
+    /// 短い名前を `name` に入れる
+    fn set_short_name(&mut self) {
+        let raw = &self.raw;
+        let mut len = 0;
+        for (range, flag) in [
+            (0..SHORT_BASE_LEN, CASE_LOWER_BASE),
+            (SHORT_BASE_LEN..SHORT_NAME_LEN, CASE_LOWER_EXT),
+        ] {
+            let part = &raw[range.clone()];
+            let part_len = part.iter().rposition(|c| *c != b' ').map_or(0, |i| i + 1);
+            if part_len == 0 {
+                continue;
+            }
+            if range.start == SHORT_BASE_LEN {
+                self.name[len] = b'.';
+                len += 1;
+            }
+            for (i, c) in part[0..part_len].iter().enumerate() {
+                let c = if i == 0 && range.start == 0 && *c == DIRENT_KANJI_E5 {
+                    DIRENT_DELETED
+                } else {
+                    *c
+                };
+                self.name[len] = if raw[DIRENT_CASE] & flag != 0 {
+                    c.to_ascii_lowercase()
+                } else {
+                    c
+                };
+                len += 1;
+            }
+        }
+        self.name_len = len;
+    }
+
+    /// UCS-2の長い名前をUTF-8にして `name` に入れる。入りきらなければ `false`
+    fn set_long_name(&mut self, units: &[u16]) -> bool {
+        let len = units.iter().position(|c| *c == 0).unwrap_or(units.len());
+        let mut name_len = 0;
+        for c in char::decode_utf16(units[0..len].iter().copied()) {
+            let Ok(c) = c else {
+                return false;
+            };
+            if name_len + c.len_utf8() > NAME_MAX {
+                return false;
+            }
+            c.encode_utf8(&mut self.name[name_len..]);
+            name_len += c.len_utf8();
+        }
+        if name_len == 0 {
+            return false;
+        }
+        self.name_len = name_len;
+        true
+    }
+}
+
+fn raw_cluster(raw: &[u8; DIRENT_SIZE]) -> u32 {
+    ((le16(raw, DIRENT_CLUSTER_HI) as u32) << 16) | le16(raw, DIRENT_CLUSTER_LO) as u32
+}
+
+fn set_raw_cluster(raw: &mut [u8; DIRENT_SIZE], cluster: u32) {
+    set_le16(raw, DIRENT_CLUSTER_HI, (cluster >> 16) as u16);
+    set_le16(raw, DIRENT_CLUSTER_LO, cluster as u16);
+}
+
+/// 更新日時 (と作成日時) を `secs` にする
+fn set_raw_time(raw: &mut [u8; DIRENT_SIZE], secs: u64, created: bool) {
+    let (date, time) = fat_time(secs);
+    set_le16(raw, DIRENT_MTIME, time);
+    set_le16(raw, DIRENT_MDATE, date);
+    set_le16(raw, DIRENT_ADATE, date);
+    if created {
+        set_le16(raw, DIRENT_CTIME, time);
+        set_le16(raw, DIRENT_CDATE, date);
+    }
+}
+
+/// マウントされたFAT32ボリューム
+struct Fat32 {
+    dev: *mut dyn BlockDevice,
+    cluster_size: u64,
+    /// 読み込みに使うFATの位置
+    fat_off: u64,
+    /// 書き込むFATの位置と大きさ (ミラーするときはすべてのFATに書く)
+    fat_start: u64,
+    fat_size: u64,
+    fat_copies: u64,
+    data_off: u64,
+    /// データ領域のクラスタ数 (クラスタ番号は2から始まる)
+    clusters: u32,
+    root_cluster: u32,
+    fsinfo_off: Option<u64>,
+    free_count: u32,
+    /// 次に空きクラスタを探し始める位置
+    next_free: u32,
+}
+
+static mut VOLUMES: [Option<Fat32>; VOLUMES_MAX] = [const { None }; VOLUMES_MAX];
+
+/// `dev` のFAT32ボリュームを読み込む
+pub unsafe fn mount(dev: *mut dyn BlockDevice) -> Result<&'static mut dyn FileSystem, ()> {
+    let volumes = &mut *ptr::addr_of_mut!(VOLUMES);
+    if volumes
+        .iter()
+        .flatten()
+        .any(|v| v.dev as *mut u8 == dev as *mut u8)
+    {
+        warn!("fat32: already mounted");
+        return Err(());
+    }
+    let slot = volumes.iter_mut().find(|slot| slot.is_none()).ok_or(())?;
+
+    let mut boot = [0; SECTOR_SIZE as usize];
+    bcache::read(dev, 0, &mut boot)?;
+    if boot[BOOT_SIGNATURE_OFFSET..] != BOOT_SIGNATURE {
+        return Err(());
+    }
+
+    let bytes_per_sector = le16(&boot, BPB_BYTES_PER_SECTOR) as u64;
+    let sectors_per_cluster = boot[BPB_SECTORS_PER_CLUSTER] as u64;
+    let reserved = le16(&boot, BPB_RESERVED_SECTORS) as u64;
+    let num_fats = boot[BPB_NUM_FATS] as u64;
+    if !(512..=4096).contains(&bytes_per_sector)
+        || !bytes_per_sector.is_power_of_two()
+        || !sectors_per_cluster.is_power_of_two()
+        || reserved == 0
+        || num_fats == 0
+    {
+        return Err(());
+    }
+    // FAT12/16はルートディレクトリの大きさと16ビットのFATサイズが0でない
+    if le16(&boot, BPB_ROOT_ENTRIES) != 0 || le16(&boot, BPB_FAT_SIZE16) != 0 {
+        warn!("fat32: FAT12/FAT16 is not supported");
+        return Err(());
+    }
+
+    let total_sectors = match le16(&boot, BPB_TOTAL_SECTORS16) {
+        0 => le32(&boot, BPB_TOTAL_SECTORS32) as u64,
+        n => n as u64,
+    };
+    let fat_sectors = le32(&boot, BPB_FAT_SIZE32) as u64;
+    let data_start = reserved + num_fats * fat_sectors;
+    if fat_sectors == 0
+        || total_sectors <= data_start
+        || total_sectors * bytes_per_sector > (*dev).capacity_sectors() * SECTOR_SIZE as u64
+    {
+        warn!("fat32: invalid BPB");
+        return Err(());
+    }
+
+    // FATに入りきらないクラスタは使わない
+    let fat_size = fat_sectors * bytes_per_sector;
+    let clusters = ((total_sectors - data_start) / sectors_per_cluster)
+        .min(fat_size / 4 - FIRST_CLUSTER as u64)
+        .min((FAT_BAD - FIRST_CLUSTER) as u64) as u32;
+    let root_cluster = le32(&boot, BPB_ROOT_CLUSTER);
+    if root_cluster < FIRST_CLUSTER || root_cluster >= FIRST_CLUSTER + clusters {
+        warn!("fat32: invalid root cluster {root_cluster}");
+        return Err(());
+    }
+
+    let ext_flags = le16(&boot, BPB_EXT_FLAGS);
+    let fat_start = reserved * bytes_per_sector;
+    let (fat_off, fat_start, fat_copies) = if ext_flags & EXT_FLAGS_NO_MIRROR != 0 {
+        let active = fat_start + (ext_flags & 0xf) as u64 * fat_size;
+        (active, active, 1)
+    } else {
+        (fat_start, fat_start, num_fats)
+    };
+
+    let mut volume = Fat32 {
+        dev,
+        cluster_size: sectors_per_cluster * bytes_per_sector,
+        fat_off,
+        fat_start,
+        fat_size,
+        fat_copies,
+        data_off: data_start * bytes_per_sector,
+        clusters,
+        root_cluster,
+        fsinfo_off: None,
+        free_count: FSINFO_UNKNOWN,
+        next_free: FIRST_CLUSTER,
+    };
+
+    // FSInfoの空きクラスタ数と次の空きクラスタのヒントを使う
+    let fsinfo_sector = le16(&boot, BPB_FSINFO_SECTOR) as u64;
+    if fsinfo_sector != 0 && fsinfo_sector < reserved {
+        let off = fsinfo_sector * bytes_per_sector;
+        let mut fsinfo = [0; SECTOR_SIZE as usize];
+        bcache::read(dev, off, &mut fsinfo)?;
+        if le32(&fsinfo, 0) == FSINFO_LEAD_SIG
+            && le32(&fsinfo, FSINFO_STRUCT_SIG_OFFSET) == FSINFO_STRUCT_SIG
+            && le32(&fsinfo, FSINFO_TRAIL_SIG_OFFSET) == FSINFO_TRAIL_SIG
+        {
+            volume.fsinfo_off = Some(off);
+            let free_count = le32(&fsinfo, FSINFO_FREE_COUNT);
+            if free_count <= clusters {
+                volume.free_count = free_count;
+            }
+            let next_free = le32(&fsinfo, FSINFO_NEXT_FREE);
+            if volume.is_valid_cluster(next_free) {
+                volume.next_free = next_free;
+            }
+        }
+    }
+
+    info!(
+        "fat32: {clusters} clusters of {} bytes",
+        volume.cluster_size
+    );
+    *slot = Some(volume);
+    Ok(slot.as_mut().unwrap())
+}
+
+impl Fat32 {
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..(FIRST_CLUSTER + self.clusters)).contains(&cluster)
+    }
+
+    /// ファイルのエントリ `raw` の先頭クラスタ。空のファイルなら0
+    fn file_cluster(&self, raw: &[u8; DIRENT_SIZE]) -> Result<u32, ()> {
+        let cluster = raw_cluster(raw);
+        if (cluster == 0 && le32(raw, DIRENT_SIZE_OFFSET) == 0) || self.is_valid_cluster(cluster) {
+            return Ok(cluster);
+        }
+        warn!("fat32: invalid start cluster {cluster}");
+        Err(())
+    }
+
+    fn cluster_off(&self, cluster: u32) -> u64 {
+        self.data_off + (cluster - FIRST_CLUSTER) as u64 * self.cluster_size
+    }
+
+    /// `cluster` から `count` クラスタ分のセクタの範囲
+    fn cluster_sectors(&self, cluster: u32, count: u32) -> Range<u64> {
+        let start = self.cluster_off(cluster) / SECTOR_SIZE as u64;
+        start..(start + count as u64 * self.cluster_size / SECTOR_SIZE as u64)
+    }
+
+    unsafe fn fat_entry(&self, cluster: u32) -> Result<u32, ()> {
+        let mut buf = [0; 4];
+        bcache::read(self.dev, self.fat_off + cluster as u64 * 4, &mut buf)?;
+        Ok(u32::from_le_bytes(buf) & FAT_ENTRY_MASK)
+    }
+
+    /// すべてのFATのエントリを書き換える (予約されている上位4ビットは残す)
+    unsafe fn set_fat_entry(&mut self, cluster: u32, value: u32) -> Result<(), ()> {
+        for i in 0..self.fat_copies {
+            let off = self.fat_start + i * self.fat_size + cluster as u64 * 4;
+            let mut buf = [0; 4];
+            bcache::read(self.dev, off, &mut buf)?;
+            let entry = (u32::from_le_bytes(buf) & !FAT_ENTRY_MASK) | value;
+            bcache::write(self.dev, off, &entry.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// チェインで `cluster` の次のクラスタ。終わりなら `None`
+    unsafe fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, ()> {
+        let next = self.fat_entry(cluster)?;
+        if next >= FAT_EOC_MIN {
+            return Ok(None);
+        }
+        if !self.is_valid_cluster(next) {
+            warn!("fat32: broken cluster chain at {cluster}");
+            return Err(());
+        }
+        Ok(Some(next))
+    }
+
+    /// `first` から始まるチェインの `index` 番目のクラスタ
+    unsafe fn cluster_at(&self, first: u32, index: u64) -> Result<u32, ()> {
+        let mut cluster = first;
+        for _ in 0..index {
+            cluster = self.next_cluster(cluster)?.ok_or(())?;
+        }
+        Ok(cluster)
+    }
+
+    /// FSInfoの空きクラスタ数とヒントを更新する
+    unsafe fn update_fsinfo(&mut self) -> Result<(), ()> {
+        let Some(off) = self.fsinfo_off else {
+            return Ok(());
+        };
+        let mut buf = [0; 8];
+        set_le32(&mut buf, 0, self.free_count);
+        set_le32(&mut buf, 4, self.next_free);
+        bcache::write(self.dev, off + FSINFO_FREE_COUNT as u64, &buf)
+    }
+
+    /// 0で埋めたクラスタを確保し、`prev` があればその後ろにつなげる
+    unsafe fn alloc_cluster(&mut self, prev: Option<u32>) -> Result<u32, ()> {
+        let start = self.next_free - FIRST_CLUSTER;
+        let mut found = None;
+        for i in 0..self.clusters {
+            let cluster = FIRST_CLUSTER + (start + i) % self.clusters;
+            if self.fat_entry(cluster)? == FAT_FREE {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let Some(cluster) = found else {
+            warn!("fat32: no free clusters");
+            return Err(());
+        };
+
+        bcache::write_zeroes(self.dev, self.cluster_sectors(cluster, 1))?;
+        self.set_fat_entry(cluster, FAT_EOC)?;
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, cluster)?;
+        }
+
+        if self.free_count != FSINFO_UNKNOWN {
+            self.free_count = self.free_count.saturating_sub(1);
+        }
+        self.next_free = if cluster + 1 < FIRST_CLUSTER + self.clusters {
+            cluster + 1
+        } else {
+            FIRST_CLUSTER
+        };
+        self.update_fsinfo()?;
+        Ok(cluster)
+    }
+
+    /// `cluster` から始まるチェインを解放する
+    ///
+    /// 解放したクラスタは連続している範囲ごとにデバイスに破棄してよいことを伝える
+    unsafe fn free_chain(&mut self, mut cluster: u32) -> Result<(), ()> {
+        // 破棄していない連続したクラスタの範囲 (先頭, 数)
+        let mut run = (cluster, 0);
+        loop {
+            let next = self.next_cluster(cluster)?;
+            self.set_fat_entry(cluster, FAT_FREE)?;
+            if self.free_count != FSINFO_UNKNOWN {
+                self.free_count += 1;
+            }
+            run.1 += 1;
+            if next != Some(cluster + 1) {
+                // 対応していないデバイスもあるので失敗しても構わない
+                let _ = bcache::discard(self.dev, self.cluster_sectors(run.0, run.1));
+            }
+            match next {
+                Some(next) => {
+                    if next != cluster + 1 {
+                        run = (next, 0);
+                    }
+                    cluster = next;
+                }
+                None => break,
+            }
+        }
+        self.update_fsinfo()
+    }
+
+    /// `first` から始まるチェインを `count` クラスタにして、新しい先頭 (空なら0) を返す
+    unsafe fn set_chain_len(&mut self, first: u32, count: u64) -> Result<u32, ()> {
+        if count == 0 {
+            if first != 0 {
+                self.free_chain(first)?;
+            }
+            return Ok(0);
+        }
+
+        let first = match first {
+            0 => self.alloc_cluster(None)?,
+            first => first,
+        };
+        let mut cluster = first;
+        for _ in 1..count {
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None => self.alloc_cluster(Some(cluster))?,
+            };
+        }
+        if let Some(rest) = self.next_cluster(cluster)? {
+            self.set_fat_entry(cluster, FAT_EOC)?;
+            self.free_chain(rest)?;
+        }
+        Ok(first)
+    }
+
+    /// ディレクトリの次のエントリの位置に進む
+    ///
+    /// ディレクトリの終わりに来たら、`extend` なら新しいクラスタをつなげ、そうでなければ `None` を返す
+    unsafe fn next_slot(&mut self, cur: &mut Cursor, extend: bool) -> Result<Option<u64>, ()> {
+        if cur.pos == self.cluster_size {
+            // チェインが輪になった壊れたディレクトリで無限に回らないようにする
+            if cur.steps >= self.clusters {
+                warn!("fat32: directory cluster chain loops at {}", cur.cluster);
+                return Err(());
+            }
+            cur.steps += 1;
+            cur.cluster = match self.next_cluster(cur.cluster)? {
+                Some(next) => next,
+                None if extend => self.alloc_cluster(Some(cur.cluster))?,
+                None => return Ok(None),
+            };
+            cur.pos = 0;
+        }
+        let off = self.cluster_off(cur.cluster) + cur.pos;
+        cur.pos += DIRENT_SIZE as u64;
+        Ok(Some(off))
+    }
+
+    /// `cur` から次の有効なエントリを読む。長い名前があればそれを名前にする
+    unsafe fn next_entry(&mut self, cur: &mut Cursor) -> Result<Option<Entry>, ()> {
+        let mut lfn = [0u16; LFN_SLOTS_MAX * LFN_CHARS_PER_SLOT];
+        // 長い名前のエントリが正しく続いているあいだは `Some((次の番号, チェックサム))`
+        let mut pending: Option<(u8, u8)> = None;
+        let mut start = *cur;
+        let mut slots = 0;
+
+        loop {
+            let before = *cur;
+            let Some(off) = self.next_slot(cur, false)? else {
+                return Ok(None);
+            };
+            let mut raw = [0; DIRENT_SIZE];
+            bcache::read(self.dev, off, &mut raw)?;
+            match raw[0] {
+                DIRENT_END => return Ok(None),
+                DIRENT_DELETED => {
+                    pending = None;
+                    continue;
+                }
+                _ => {}
+            }
+
+            if raw[DIRENT_ATTR] == ATTR_LFN {
+                let ord = raw[0] & LFN_ORD_MASK;
+                let sum = raw[LFN_CHECKSUM];
+                if raw[0] & LFN_LAST != 0 {
+                    if ord == 0 || ord as usize > LFN_SLOTS_MAX {
+                        pending = None;
+                        continue;
+                    }
+                    lfn.fill(0);
+                    start = before;
+                    slots = 0;
+                } else if ord == 0 || pending != Some((ord, sum)) {
+                    // 最後のスロットの後の `pending` は0になるので、順番0のスロットは続きとみなさない
+                    pending = None;
+                    continue;
+                }
+                let base = (ord as usize - 1) * LFN_CHARS_PER_SLOT;
+                for (i, pos) in LFN_CHAR_OFFSETS.iter().enumerate() {
+                    lfn[base + i] = le16(&raw, *pos);
+                }
+                pending = Some((ord - 1, sum));
+                slots += 1;
+                continue;
+            }
+            if raw[DIRENT_ATTR] & ATTR_VOLUME_ID != 0 {
+                pending = None;
+                continue;
+            }
+
+            let mut entry = Entry {
+                start: before,
+                slots: 1,
+                off,
+                raw,
+                name: [0; NAME_MAX],
+                name_len: 0,
+            };
+            let sum = short_name_checksum(&raw);
+            if pending == Some((0, sum)) && entry.set_long_name(&lfn) {
+                entry.start = start;
+                entry.slots = slots + 1;
+            } else {
+                entry.set_short_name();
+            }
+            return Ok(Some(entry));
+        }
+    }
+
+    /// ディレクトリ `dir` (先頭クラスタ) から `name` を探す (ASCIIの大文字小文字は区別しない)
+    unsafe fn find(&mut self, dir: u32, name: &[u8]) -> Result<Entry, ()> {
+        let mut cur = Cursor::new(dir);
+        while let Some(entry) = self.next_entry(&mut cur)? {
+            if entry.name().eq_ignore_ascii_case(name) {
+                return Ok(entry);
+            }
+        }
+        Err(())
+    }
+
+    unsafe fn short_name_exists(&mut self, dir: u32, short: &[u8]) -> Result<bool, ()> {
+        let mut cur = Cursor::new(dir);
+        while let Some(entry) = self.next_entry(&mut cur)? {
+            if entry.raw[0..SHORT_NAME_LEN] == *short {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// 長い名前から `NAME~N.EXT` の形の重複しない短い名前を作る
+    unsafe fn generate_short_name(
+        &mut self,
+        dir: u32,
+        name: &str,
+    ) -> Result<[u8; SHORT_NAME_LEN], ()> {
+        let (base, ext) = split_ext(name);
+        let mut basis = [0; SHORT_BASE_LEN];
+        let mut basis_len = short_basis(base, &mut basis);
+        if basis_len == 0 {
+            basis[0] = b'_';
+            basis_len = 1;
+        }
+        let mut short = [b' '; SHORT_NAME_LEN];
+        short_basis(ext, &mut short[SHORT_BASE_LEN..]);
+
+        for n in 1..=SHORT_TAIL_MAX {
+            let mut tail = [0; 7];
+            let mut tail_len = 0;
+            let mut rest = n;
+            while rest > 0 {
+                tail[tail.len() - 1 - tail_len] = b'0' + (rest % 10) as u8;
+                rest /= 10;
+                tail_len += 1;
+            }
+            tail[tail.len() - 1 - tail_len] = b'~';
+            tail_len += 1;
+
+            let keep = basis_len.min(SHORT_BASE_LEN - tail_len);
+            short[0..SHORT_BASE_LEN].fill(b' ');
+            short[0..keep].copy_from_slice(&basis[0..keep]);
+            short[keep..(keep + tail_len)].copy_from_slice(&tail[(tail.len() - tail_len)..]);
+            if !self.short_name_exists(dir, &short)? {
+                return Ok(short);
+            }
+        }
+        Err(())
+    }
+
+    /// ディレクトリ `dir` の中で連続する `count` 個の空きエントリを探し、その位置を返す
+    ///
+    /// 足りなければディレクトリを伸ばす
+    unsafe fn alloc_slots(
+        &mut self,
+        dir: u32,
+        count: usize,
+    ) -> Result<[u64; LFN_SLOTS_MAX + 1], ()> {
+        let mut offs = [0; LFN_SLOTS_MAX + 1];
+        let mut found = 0;
+        let mut cur = Cursor::new(dir);
+        loop {
+            let off = self.next_slot(&mut cur, true)?.ok_or(())?;
+            let mut first = [0; 1];
+            bcache::read(self.dev, off, &mut first)?;
+            if first[0] == DIRENT_END || first[0] == DIRENT_DELETED {
+                offs[found] = off;
+                found += 1;
+                if found == count {
+                    return Ok(offs);
+                }
+            } else {
+                found = 0;
+            }
+        }
+    }
+
+    /// ディレクトリ `dir` に `name` のエントリを書き込み、その番号を返す
+    ///
+    /// `raw` の名前以外のフィールドはそのまま使う
+    unsafe fn add_entry(
+        &mut self,
+        dir: u32,
+        name: &[u8],
+        mut raw: [u8; DIRENT_SIZE],
+    ) -> Result<Ino, ()> {
+        if !valid_name(name) {
+            return Err(());
+        }
+        let name = core::str::from_utf8(name).map_err(|_| ())?;
+
+        // 8.3形式で表せる名前には長い名前のエントリを作らない
+        let (short, case, long) = match exact_short_name(name) {
+            Some((short, case)) if !self.short_name_exists(dir, &short)? => (short, case, false),
+            _ => (self.generate_short_name(dir, name)?, 0, true),
+        };
+        let mut units = [0xffffu16; LFN_SLOTS_MAX * LFN_CHARS_PER_SLOT];
+        let mut len = 0;
+        for c in name.encode_utf16() {
+            units[len] = c;
+            len += 1;
+        }
+        let lfn_slots = if long {
+            // 13文字ちょうどでなければ名前の後ろに0を1つ入れる
+            if len % LFN_CHARS_PER_SLOT != 0 {
+                units[len] = 0;
+            }
+            len.div_ceil(LFN_CHARS_PER_SLOT)
+        } else {
+            0
+        };
+
+        let offs = self.alloc_slots(dir, lfn_slots + 1)?;
+        let sum = short_name_checksum(&short);
+        // 長い名前のエントリは最後の部分から順に並べる
+        for (i, off) in offs[0..lfn_slots].iter().enumerate() {
+            let ord = lfn_slots - i;
+            let mut slot = [0; DIRENT_SIZE];
+            slot[0] = ord as u8 | if i == 0 { LFN_LAST } else { 0 };
+            slot[DIRENT_ATTR] = ATTR_LFN;
+            slot[LFN_CHECKSUM] = sum;
+            let base = (ord - 1) * LFN_CHARS_PER_SLOT;
+            for (j, pos) in LFN_CHAR_OFFSETS.iter().enumerate() {
+                set_le16(&mut slot, *pos, units[base + j]);
+            }
+            bcache::write(self.dev, *off, &slot)?;
+        }
+
+        raw[0..SHORT_NAME_LEN].copy_from_slice(&short);
+        raw[DIRENT_CASE] = case;
+        let off = offs[lfn_slots];
+        bcache::write(self.dev, off, &raw)?;
+        Ok(off)
+    }
+
+    /// 長い名前のエントリも含めて `entry` を削除済みにする
+    unsafe fn remove_entry(&mut self, entry: &Entry) -> Result<(), ()> {
+        let mut cur = entry.start;
+        for _ in 0..entry.slots {
+            let off = self.next_slot(&mut cur, false)?.ok_or(())?;
+            bcache::write(self.dev, off, &[DIRENT_DELETED])?;
+        }
+        Ok(())
+    }
+
+    /// `ino` の短い名前のエントリを読む
+    unsafe fn raw_entry(&self, ino: Ino) -> Result<[u8; DIRENT_SIZE], ()> {
+        if ino < self.data_off {
+            return Err(());
+        }
+        let mut raw = [0; DIRENT_SIZE];
+        bcache::read(self.dev, ino, &mut raw)?;
+        if raw[0] == DIRENT_END || raw[0] == DIRENT_DELETED || raw[DIRENT_ATTR] == ATTR_LFN {
+            return Err(());
+        }
+        Ok(raw)
+    }
+
+    /// ディレクトリ `ino` の先頭クラスタ
+    unsafe fn dir_cluster(&self, ino: Ino) -> Result<u32, ()> {
+        if ino == ROOT_INO {
+            return Ok(self.root_cluster);
+        }
+        let raw = self.raw_entry(ino)?;
+        let cluster = raw_cluster(&raw);
+        if raw[DIRENT_ATTR] & ATTR_DIRECTORY == 0 || !self.is_valid_cluster(cluster) {
+            return Err(());
+        }
+        Ok(cluster)
+    }
+
+    /// ディレクトリ (先頭クラスタ) の `..` が指す親ディレクトリの先頭クラスタ
+    unsafe fn parent_cluster(&self, dir: u32) -> Result<u32, ()> {
+        if dir == self.root_cluster {
+            return Ok(dir);
+        }
+        let mut raw = [0; DIRENT_SIZE];
+        bcache::read(
+            self.dev,
+            self.cluster_off(dir) + DIRENT_SIZE as u64,
+            &mut raw,
+        )?;
+        // `..` がルートディレクトリなら0が入っている
+        match raw_cluster(&raw) {
+            0 => Ok(self.root_cluster),
+            cluster if self.is_valid_cluster(cluster) => Ok(cluster),
+            _ => Err(()),
+        }
+    }
+
+    /// 先頭クラスタが `dir` のディレクトリの番号
+    unsafe fn dir_ino(&mut self, dir: u32) -> Result<Ino, ()> {
+        if dir == self.root_cluster {
+            return Ok(ROOT_INO);
+        }
+        let parent = self.parent_cluster(dir)?;
+        let mut cur = Cursor::new(parent);
+        while let Some(entry) = self.next_entry(&mut cur)? {
+            if entry.is_dir() && !entry.is_dot() && entry.cluster() == dir {
+                return Ok(entry.off);
+            }
+        }
+        Err(())
+    }
+
+    /// ディレクトリ (先頭クラスタ) が空か
+    unsafe fn is_empty_dir(&mut self, dir: u32) -> Result<bool, ()> {
+        let mut cur = Cursor::new(dir);
+        while let Some(entry) = self.next_entry(&mut cur)? {
+            if !entry.is_dot() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// ファイルの中身を読み書きする。`f` にはディスク上の位置と `buf` の範囲を渡す
+    unsafe fn for_each_extent(
+        &self,
+        first: u32,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(u64, core::ops::Range<usize>) -> Result<(), ()>,
+    ) -> Result<(), ()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let mut cluster = self.cluster_at(first, offset / self.cluster_size)?;
+        let mut done = 0;
+        while done < len {
+            if done > 0 {
+                cluster = self.next_cluster(cluster)?.ok_or(())?;
+            }
+            let pos = offset + done as u64;
+            let inner = pos % self.cluster_size;
+            let n = ((self.cluster_size - inner) as usize).min(len - done);
+            f(self.cluster_off(cluster) + inner, done..(done + n))?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// 通常のファイル `ino` のサイズを変える
+    unsafe fn resize(&mut self, ino: Ino, size: usize) -> Result<(), ()> {
+        let mut raw = self.raw_entry(ino)?;
+        if raw[DIRENT_ATTR] & ATTR_DIRECTORY != 0 {
+            return Err(());
+        }
+        let size = u32::try_from(size).map_err(|_| ())?;
+        let old_size = le32(&raw, DIRENT_SIZE_OFFSET);
+        let first = self.file_cluster(&raw)?;
+
+        // 最後のクラスタのファイルの末尾より後ろには古いデータが残っているかもしれない
+        let tail = old_size as u64 % self.cluster_size;
+        if size > old_size && tail != 0 {
+            let cluster = self.cluster_at(first, old_size as u64 / self.cluster_size)?;
+            let zero_len = (self.cluster_size - tail).min((size - old_size) as u64);
+            let mut done = 0;
+            while done < zero_len {
+                let n = (zero_len - done).min(SECTOR_SIZE as u64);
+                let off = self.cluster_off(cluster) + tail + done;
+                bcache::write(self.dev, off, &ZERO_SECTOR[0..(n as usize)])?;
+                done += n;
+            }
+        }
+
+        let first = self.set_chain_len(first, (size as u64).div_ceil(self.cluster_size))?;
+        set_raw_cluster(&mut raw, first);
+        set_le32(&mut raw, DIRENT_SIZE_OFFSET, size);
+        set_raw_time(&mut raw, rtc::now_secs(), false);
+        raw[DIRENT_ATTR] |= ATTR_ARCHIVE;
+        bcache::write(self.dev, ino, &raw)
+    }
+
+    /// ディレクトリ `dir` の中に、`new_dir` が `dir` 自身かその下にあるか
+    unsafe fn is_inside(&self, dir: u32, mut new_dir: u32) -> Result<bool, ()> {
+        // 壊れたディレクトリで無限に回らないようにする
+        for _ in 0..self.clusters {
+            if new_dir == dir {
+                return Ok(true);
+            }
+            if new_dir == self.root_cluster {
+                return Ok(false);
+            }
+            new_dir = self.parent_cluster(new_dir)?;
+        }
+        Err(())
+    }
+
+    /// `entry` とそのデータを削除する
+    unsafe fn remove(&mut self, entry: &Entry) -> Result<(), ()> {
+        let cluster = entry.cluster();
+        if cluster != 0 && !self.is_valid_cluster(cluster) {
+            warn!("fat32: invalid start cluster {cluster}");
+            return Err(());
+        }
+        self.remove_entry(entry)?;
+        if cluster != 0 {
+            self.free_chain(cluster)?;
+        }
+        Ok(())
+    }
+}
+
+impl FileSystem for Fat32 {
+    fn root(&self) -> Ino {
+        ROOT_INO
+    }
+
+    fn lookup(&mut self, dir: Ino, name: &[u8]) -> Result<Ino, ()> {
+        unsafe {
+            let cluster = self.dir_cluster(dir)?;
+            match name {
+                b"." => Ok(dir),
+                b".." => {
+                    let parent = self.parent_cluster(cluster)?;
+                    self.dir_ino(parent)
+                }
+                _ => Ok(self.find(cluster, name)?.off),
+            }
+        }
+    }
+
+    fn stat(&mut self, ino: Ino) -> Result<Stat, ()> {
+        if ino == ROOT_INO {
+            return Ok(Stat {
+                mode: S_IFDIR | 0o755,
+                uid: 0,
+                gid: 0,
+                nlink: 1,
+                size: 0,
+                mtime: 0,
+            });
+        }
+
+        let raw = unsafe { self.raw_entry(ino)? };
+        let attr = raw[DIRENT_ATTR];
+        let (type_, size) = if attr & ATTR_DIRECTORY != 0 {
+            (S_IFDIR | 0o755, 0)
+        } else {
+            (S_IFREG | 0o644, le32(&raw, DIRENT_SIZE_OFFSET))
+        };
+        // FATには所有者がないので、読み取り専用の属性だけをパーミッションに反映する
+        let mode = if attr & ATTR_READ_ONLY != 0 {
+            type_ & !0o222
+        } else {
+            type_
+        };
+        Ok(Stat {
+            mode,
+            uid: 0,
+            gid: 0,
+            nlink: 1,
+            size: size as u64,
+            mtime: unix_time(le16(&raw, DIRENT_MDATE), le16(&raw, DIRENT_MTIME)),
+        })
+    }
+
+    fn read(&mut self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, ()> {
+        unsafe {
+            let raw = self.raw_entry(ino)?;
+            if raw[DIRENT_ATTR] & ATTR_DIRECTORY != 0 {
+                return Err(());
+            }
+            let size = le32(&raw, DIRENT_SIZE_OFFSET) as usize;
+            if offset >= size {
+                return Ok(0);
+            }
+            let len = buf.len().min(size - offset);
+            let dev = self.dev;
+            let first = self.file_cluster(&raw)?;
+            self.for_each_extent(first, offset as u64, len, |off, range| {
+                bcache::read(dev, off, &mut buf[range])
+            })?;
+            Ok(len)
+        }
+    }
+
+    fn write(&mut self, ino: Ino, offset: usize, data: &[u8]) -> Result<usize, ()> {
+        unsafe {
+            let raw = self.raw_entry(ino)?;
+            let size = le32(&raw, DIRENT_SIZE_OFFSET) as usize;
+            let end = offset + data.len();
+            // 伸ばしたときに更新日時も変わる
+            self.resize(ino, size.max(end))?;
+
+            let raw = self.raw_entry(ino)?;
+            let dev = self.dev;
+            self.for_each_extent(
+                raw_cluster(&raw),
+                offset as u64,
+                data.len(),
+                |off, range| bcache::write(dev, off, &data[range]),
+            )?;
+            Ok(data.len())
+        }
+    }
+
+    fn truncate(&mut self, ino: Ino, size: usize) -> Result<(), ()> {
+        unsafe { self.resize(ino, size) }
+    }
+
+    fn readlink(&mut self, _ino: Ino, _buf: &mut [u8]) -> Result<usize, ()> {
+        // FATにはシンボリックリンクがない
+        Err(())
+    }
+
+    fn readdir(&mut self, dir: Ino, index: usize) -> Result<Option<DirEntry>, ()> {
+        unsafe {
+            let mut cur = Cursor::new(self.dir_cluster(dir)?);
+            let mut i = 0;
+            while let Some(entry) = self.next_entry(&mut cur)? {
+                if entry.is_dot() {
+                    continue;
+                }
+                if i == index {
+                    let kind = if entry.is_dir() {
+                        FileType::Directory
+                    } else {
+                        FileType::Regular
+                    };
+                    return DirEntry::new(entry.name(), kind).map(Some);
+                }
+                i += 1;
+            }
+            Ok(None)
+        }
+    }
+
+    fn create(&mut self, dir: Ino, name: &[u8], kind: FileType) -> Result<Ino, ()> {
+        unsafe {
+            let parent = self.dir_cluster(dir)?;
+            if self.find(parent, name).is_ok() {
+                return Err(());
+            }
+
+            let now = rtc::now_secs();
+            let mut raw = [0; DIRENT_SIZE];
+            set_raw_time(&mut raw, now, true);
+            match kind {
+                FileType::Regular => raw[DIRENT_ATTR] = ATTR_ARCHIVE,
+                FileType::Directory => {
+                    // `.` と `..` を入れたクラスタを用意する
+                    let cluster = self.alloc_cluster(None)?;
+                    raw[DIRENT_ATTR] = ATTR_DIRECTORY;
+                    let mut dot = raw;
+                    dot[0..SHORT_NAME_LEN].copy_from_slice(b".          ");
+                    set_raw_cluster(&mut dot, cluster);
+                    bcache::write(self.dev, self.cluster_off(cluster), &dot)?;
+                    dot[0..SHORT_NAME_LEN].copy_from_slice(b"..         ");
+                    let parent_ref = if parent == self.root_cluster {
+                        0
+                    } else {
+                        parent
+                    };
+                    set_raw_cluster(&mut dot, parent_ref);
+                    bcache::write(
+                        self.dev,
+                        self.cluster_off(cluster) + DIRENT_SIZE as u64,
+                        &dot,
+                    )?;
+                    set_raw_cluster(&mut raw, cluster);
+                }
+                FileType::Symlink => return Err(()),
+            }
+
+            let result = self.add_entry(parent, name, raw);
+            if result.is_err() && kind == FileType::Directory {
+                self.free_chain(raw_cluster(&raw))?;
+            }
+            result
+        }
+    }
+
+    fn unlink(&mut self, dir: Ino, name: &[u8]) -> Result<(), ()> {
+        unsafe {
+            let entry = self.find(self.dir_cluster(dir)?, name)?;
+            if entry.is_dir() || entry.is_dot() {
+                return Err(());
+            }
+            self.remove(&entry)
+        }
+    }
+
+    fn rmdir(&mut self, dir: Ino, name: &[u8]) -> Result<(), ()> {
+        unsafe {
+            let entry = self.find(self.dir_cluster(dir)?, name)?;
+            if !entry.is_dir() || entry.is_dot() || !self.is_empty_dir(entry.cluster())? {
+                return Err(());
+            }
+            self.remove(&entry)
+        }
+    }
+
+    fn rename(
+        &mut self,
+        old_dir: Ino,
+        old_name: &[u8],
+        new_dir: Ino,
+        new_name: &[u8],
+    ) -> Result<(), ()> {
+        unsafe {
+            let old_parent = self.dir_cluster(old_dir)?;
+            let new_parent = self.dir_cluster(new_dir)?;
+            let entry = self.find(old_parent, old_name)?;
+            if entry.is_dot() || !valid_name(new_name) {
+                return Err(());
+            }
+            // ディレクトリを自分の下には移せない
+            if entry.is_dir() && self.is_inside(entry.cluster(), new_parent)? {
+                return Err(());
+            }
+
+            if let Ok(target) = self.find(new_parent, new_name) {
+                if target.off == entry.off {
+                    // 大文字と小文字だけを変えるとき以外は何もしない
+                    if target.name() == new_name {
+                        return Ok(());
+                    }
+                } else {
+                    if target.is_dir() != entry.is_dir()
+                        || (target.is_dir() && !self.is_empty_dir(target.cluster())?)
+                    {
+                        return Err(());
+                    }
+                    self.remove(&target)?;
+                }
+            }
+
+            // 新しいエントリを書いてから古いエントリを消す
+            self.add_entry(new_parent, new_name, entry.raw)?;
+            self.remove_entry(&entry)?;
+
+            if entry.is_dir() && new_parent != old_parent {
+                let mut dotdot = [0; DIRENT_SIZE];
+                let off = self.cluster_off(entry.cluster()) + DIRENT_SIZE as u64;
+                bcache::read(self.dev, off, &mut dotdot)?;
+                let parent_ref = if new_parent == self.root_cluster {
+                    0
+                } else {
+                    new_parent
+                };
+                set_raw_cluster(&mut dotdot, parent_ref);
+                bcache::write(self.dev, off, &dotdot)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn fsync(&mut self, _ino: Ino) -> Result<(), ()> {
+        // ファイルの変更はFATやディレクトリにも及ぶので、ボリューム全体を書き戻す
+        self.sync()
+    }
+
+    fn sync(&mut self) -> Result<(), ()> {
+        unsafe { bcache::sync(self.dev) }
+    }
+}
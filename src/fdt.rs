@@ -0,0 +1,330 @@
+use crate::{debug, types::PhysAddr, warn};
+use core::{mem, slice};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+const FDT_DEPTH_MAX: usize = 16;
+const MEMORY_REGIONS_MAX: usize = 4;
+pub const VIRTIO_MMIO_MAX: usize = 8;
+const BOOTARGS_MAX: usize = 256;
+
+#[repr(C)]
+#[derive(Debug)]
+struct FdtHeader {
+    magic: u32,
+    totalsize: u32,
+    off_dt_struct: u32,
+    off_dt_strings: u32,
+    off_mem_rsvmap: u32,
+    version: u32,
+    last_comp_version: u32,
+    boot_cpuid_phys: u32,
+    size_dt_strings: u32,
+    size_dt_struct: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub base: PhysAddr,
+    pub size: u64,
+}
+
+impl Region {
+    const fn new() -> Self {
+        Self {
+            base: PhysAddr::new(0),
+            size: 0,
+        }
+    }
+}
+
+/// 割り込み番号付きのデバイス
+#[derive(Debug, Clone, Copy)]
+pub struct Device {
+    pub reg: Region,
+    pub irq: u32,
+}
+
+impl Device {
+    const fn new() -> Self {
+        Self {
+            reg: Region::new(),
+            irq: 0,
+        }
+    }
+}
+
+/// デバイスツリーから見つけたハードウェアの情報
+#[derive(Debug)]
+pub struct Platform {
+    pub dtb: Region,
+    pub memory: [Region; MEMORY_REGIONS_MAX],
+    pub memory_len: usize,
+    pub timebase_freq: u64,
+    pub virtio_mmio: [Device; VIRTIO_MMIO_MAX],
+    pub virtio_mmio_len: usize,
+    pub plic: Option<Region>,
+    pub clint: Option<Region>,
+    pub uart: Option<Device>,
+    pub rtc: Option<Region>,
+    pub bootargs: [u8; BOOTARGS_MAX],
+    pub bootargs_len: usize,
+}
+
+impl Platform {
+    const fn new() -> Self {
+        Self {
+            dtb: Region::new(),
+            memory: [Region::new(); MEMORY_REGIONS_MAX],
+            memory_len: 0,
+            timebase_freq: 0,
+            virtio_mmio: [Device::new(); VIRTIO_MMIO_MAX],
+            virtio_mmio_len: 0,
+            plic: None,
+            clint: None,
+            uart: None,
+            rtc: None,
+            bootargs: [0; BOOTARGS_MAX],
+            bootargs_len: 0,
+        }
+    }
+}
+
+pub static mut PLATFORM: Platform = Platform::new();
+
+/// パース中のノードのプロパティ
+#[derive(Clone, Copy)]
+struct Node {
+    name: &'static [u8],
+    address_cells: u32,
+    size_cells: u32,
+    device_type: &'static [u8],
+    compatible: &'static [u8],
+    reg: &'static [u8],
+    interrupts: &'static [u8],
+    timebase_freq: &'static [u8],
+    bootargs: &'static [u8],
+}
+
+impl Node {
+    const fn new() -> Self {
+        Self {
+            name: &[],
+            address_cells: 2,
+            size_cells: 1,
+            device_type: &[],
+            compatible: &[],
+            reg: &[],
+            interrupts: &[],
+            timebase_freq: &[],
+            bootargs: &[],
+        }
+    }
+
+    /// `compatible` の文字列リストに `name` が含まれているか
+    fn is_compatible(&self, name: &str) -> bool {
+        self.compatible
+            .split(|c| *c == b'\0')
+            .any(|s| s == name.as_bytes())
+    }
+
+    /// 親ノードの `#address-cells` と `#size-cells` に従って `reg` の最初の組を読む
+    fn first_reg(&self, parent: &Node) -> Option<Region> {
+        let addr_len = parent.address_cells as usize * 4;
+        let size_len = parent.size_cells as usize * 4;
+        if self.reg.len() < addr_len + size_len {
+            return None;
+        }
+        Some(Region {
+            base: PhysAddr::new(read_cells(&self.reg[0..addr_len])),
+            size: read_cells(&self.reg[addr_len..(addr_len + size_len)]),
+        })
+    }
+
+    fn first_irq(&self) -> u32 {
+        if self.interrupts.len() < 4 {
+            return 0;
+        }
+        read_cells(&self.interrupts[0..4]) as u32
+    }
+}
+
+fn be32(p: *const u8) -> u32 {
+    u32::from_be(unsafe { (p as *const u32).read_unaligned() })
+}
+
+/// ビッグエンディアンのセル列を整数として読む
+fn read_cells(cells: &[u8]) -> u64 {
+    cells
+        .chunks(4)
+        .fold(0, |v, c| (v << 32) | be32(c.as_ptr()) as u64)
+}
+
+fn cstr(p: *const u8) -> &'static [u8] {
+    let mut len = 0;
+    unsafe {
+        while *p.add(len) != b'\0' {
+            len += 1;
+        }
+        slice::from_raw_parts(p, len)
+    }
+}
+
+/// OpenSBIから渡されたFDTをパースして `PLATFORM` に記録する
+pub unsafe fn parse(dtb: PhysAddr) -> Result<(), ()> {
+    let base = dtb.as_u64() as *const u8;
+    if base.is_null() {
+        return Err(());
+    }
+
+    let header = (base as *const FdtHeader).as_ref().unwrap();
+    if u32::from_be(header.magic) != FDT_MAGIC {
+        warn!("fdt: invalid magic: {:x}", u32::from_be(header.magic));
+        return Err(());
+    }
+
+    let platform = &mut PLATFORM;
+    platform.dtb = Region {
+        base: dtb,
+        size: u32::from_be(header.totalsize) as u64,
+    };
+
+    let strings = base.add(u32::from_be(header.off_dt_strings) as usize);
+    let mut p = base.add(u32::from_be(header.off_dt_struct) as usize);
+    let mut nodes = [Node::new(); FDT_DEPTH_MAX];
+    let mut depth = 0;
+
+    loop {
+        let token = be32(p);
+        p = p.add(mem::size_of::<u32>());
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = cstr(p);
+                p = p.add((name.len() + 1 + 3) & !3);
+                depth += 1;
+                if depth >= FDT_DEPTH_MAX {
+                    warn!("fdt: tree is too deep");
+                    return Err(());
+                }
+                nodes[depth] = Node::new();
+                nodes[depth].name = name;
+            }
+            FDT_END_NODE => {
+                if depth == 0 {
+                    return Err(());
+                }
+                let node = nodes[depth];
+                let parent = nodes[depth - 1];
+                register_node(platform, &node, &parent);
+                depth -= 1;
+            }
+            FDT_PROP => {
+                let len = be32(p) as usize;
+                let nameoff = be32(p.add(4)) as usize;
+                let value = slice::from_raw_parts(p.add(8), len);
+                p = p.add((8 + len + 3) & !3);
+
+                let node = &mut nodes[depth];
+                match cstr(strings.add(nameoff)) {
+                    b"#address-cells" => node.address_cells = read_cells(value) as u32,
+                    b"#size-cells" => node.size_cells = read_cells(value) as u32,
+                    b"device_type" if len > 0 => node.device_type = cstr(value.as_ptr()),
+                    b"compatible" => node.compatible = value,
+                    b"reg" => node.reg = value,
+                    b"interrupts" => node.interrupts = value,
+                    b"timebase-frequency" => node.timebase_freq = value,
+                    b"bootargs" if len > 0 => node.bootargs = cstr(value.as_ptr()),
+                    _ => {}
+                }
+            }
+            FDT_NOP => {}
+            FDT_END => break,
+            _ => {
+                warn!("fdt: unknown token: {token:x}");
+                return Err(());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn register_node(platform: &mut Platform, node: &Node, parent: &Node) {
+    if !node.timebase_freq.is_empty() {
+        platform.timebase_freq = read_cells(node.timebase_freq);
+    }
+
+    if node.name == b"chosen" && !node.bootargs.is_empty() {
+        let len = node.bootargs.len().min(BOOTARGS_MAX);
+        platform.bootargs[0..len].copy_from_slice(&node.bootargs[0..len]);
+        platform.bootargs_len = len;
+    }
+
+    let reg = match node.first_reg(parent) {
+        Some(reg) => reg,
+        None => return,
+    };
+
+    if node.device_type == b"memory" {
+        if platform.memory_len < MEMORY_REGIONS_MAX {
+            platform.memory[platform.memory_len] = reg;
+            platform.memory_len += 1;
+        }
+    } else if node.is_compatible("virtio,mmio") {
+        if platform.virtio_mmio_len < VIRTIO_MMIO_MAX {
+            platform.virtio_mmio[platform.virtio_mmio_len] = Device {
+                reg,
+                irq: node.first_irq(),
+            };
+            platform.virtio_mmio_len += 1;
+        }
+    } else if node.is_compatible("riscv,plic0") || node.is_compatible("sifive,plic-1.0.0") {
+        platform.plic = Some(reg);
+    } else if node.is_compatible("riscv,clint0") || node.is_compatible("sifive,clint0") {
+        platform.clint = Some(reg);
+    } else if node.is_compatible("ns16550a") {
+        platform.uart = Some(Device {
+            reg,
+            irq: node.first_irq(),
+        });
+    } else if node.is_compatible("google,goldfish-rtc") {
+        platform.rtc = Some(reg);
+    }
+}
+
+pub unsafe fn dump() {
+    let platform = &PLATFORM;
+    for i in 0..platform.memory_len {
+        let mem = platform.memory[i];
+        debug!(
+            "fdt: memory: {:#x}-{:#x}",
+            mem.base.as_u64(),
+            mem.base.as_u64() + mem.size
+        );
+    }
+    debug!("fdt: timebase-frequency: {}", platform.timebase_freq);
+    for i in 0..platform.virtio_mmio_len {
+        let dev = platform.virtio_mmio[i];
+        debug!(
+            "fdt: virtio-mmio: {:#x}, irq={}",
+            dev.reg.base.as_u64(),
+            dev.irq
+        );
+    }
+    if let Some(plic) = platform.plic {
+        debug!("fdt: plic: {:#x}", plic.base.as_u64());
+    }
+    if let Some(clint) = platform.clint {
+        debug!("fdt: clint: {:#x}", clint.base.as_u64());
+    }
+    if let Some(uart) = platform.uart {
+        debug!("fdt: uart: {:#x}, irq={}", uart.reg.base.as_u64(), uart.irq);
+    }
+    if let Some(rtc) = platform.rtc {
+        debug!("fdt: rtc: {:#x}", rtc.base.as_u64());
+    }
+}
@@ -1,7 +1,12 @@
-use crate::{println, read_csr, syscall::handle_syscall, write_csr};
+use crate::{plic, println, process, read_csr, syscall::handle_syscall, timer, warn, write_csr};
 use core::arch::global_asm;
 
+const SCAUSE_INTERRUPT: u64 = 1 << 63;
 const SCAUSE_ECALL: u64 = 8;
+const IRQ_S_TIMER: u64 = 5;
+const IRQ_S_EXTERNAL: u64 = 9;
+const IRQ_HANDLERS_MAX: usize = 64;
+const SSTATUS_SPP: u64 = 1 << 8;
 
 #[repr(C, packed)]
 #[derive(Debug)]
@@ -127,8 +132,21 @@ fn handle_trap(f: *mut TrapFrame) {
     let scause = read_csr!("scause");
     let stval = read_csr!("stval");
     let mut user_pc = read_csr!("sepc");
+    // 割り込み元のモードなどを保持しておき、他のプロセスに切り替わっても復元できるようにする
+    let sstatus = read_csr!("sstatus");
 
-    if scause == SCAUSE_ECALL {
+    if scause & SCAUSE_INTERRUPT != 0 {
+        match scause & !SCAUSE_INTERRUPT {
+            IRQ_S_TIMER => unsafe { timer::handle_interrupt() },
+            IRQ_S_EXTERNAL => unsafe { handle_external_interrupt() },
+            irq => panic!("unexpected interrupt irq={irq:x}, sepc={user_pc:x}"),
+        }
+
+        // ユーザーモードで割り込まれた場合はタイムスライスを確認する
+        if sstatus & SSTATUS_SPP == 0 {
+            unsafe { process::preempt() };
+        }
+    } else if scause == SCAUSE_ECALL {
         handle_syscall(f);
         user_pc += 4;
     } else {
@@ -137,9 +155,39 @@ fn handle_trap(f: *mut TrapFrame) {
         panic!("unexpected trap scause={scause:x}, stval={stval:x}, sepc={user_pc:x}");
     }
 
+    write_csr!("sstatus", sstatus);
     write_csr!("sepc", user_pc);
 }
 
+/// PLICの割り込み番号ごとのハンドラ
+static mut IRQ_HANDLERS: [Option<fn(u32)>; IRQ_HANDLERS_MAX] = [None; IRQ_HANDLERS_MAX];
+
+/// 外部割り込み `irq` のハンドラを登録し、PLICで割り込みを許可する
+pub unsafe fn register_irq(irq: u32, handler: fn(u32)) -> Result<(), ()> {
+    let irq_idx = irq as usize;
+    if irq == 0 || irq_idx >= IRQ_HANDLERS_MAX || IRQ_HANDLERS[irq_idx].is_some() {
+        return Err(());
+    }
+    IRQ_HANDLERS[irq_idx] = Some(handler);
+    plic::enable(irq);
+    Ok(())
+}
+
+unsafe fn handle_external_interrupt() {
+    loop {
+        let irq = plic::claim();
+        if irq == 0 {
+            break;
+        }
+
+        match IRQ_HANDLERS.get(irq as usize).copied().flatten() {
+            Some(handler) => handler(irq),
+            None => warn!("unhandled external interrupt irq={irq}"),
+        }
+        plic::complete(irq);
+    }
+}
+
 global_asm!(
     r#"
 .align 8
@@ -2,27 +2,46 @@
 #![no_main]
 #![feature(offset_of)]
 
+mod bcache;
+mod block;
+mod cmdline;
 mod elf;
+mod ext2;
+mod fat32;
+mod fdt;
 mod handler;
 mod memory;
 mod paging;
+mod partition;
+mod plic;
 mod print;
 mod process;
+mod ramdisk;
+mod rtc;
 mod sbi;
 mod syscall;
 mod tarfs;
+mod timer;
 mod types;
+mod uart;
 mod utils;
+mod vfs;
+mod virtio;
 mod virtio_blk;
+mod virtio_mmio;
 
 use crate::{
+    cmdline::CMDLINE,
     elf::ElfHeader,
+    memory::{alloc_pages, PAGE_SIZE},
     process::{process_yield, CURRENT_PROC, IDLE_PROC},
+    types::PhysAddr,
+    utils::align_up,
 };
 use core::{
     arch::{asm, global_asm},
     panic::PanicInfo,
-    ptr,
+    ptr, slice,
 };
 use process::Process;
 
@@ -30,28 +49,64 @@ extern "C" {
     pub static __kernel_base: u8;
     static mut __bss: u8;
     static __bss_end: u8;
-    pub static __free_ram_end: u8;
     fn kernel_entry();
 }
 
 #[no_mangle]
-fn kernel_main() -> ! {
+fn kernel_main(hartid: u64, dtb: u64) -> ! {
     clear_bss();
 
     write_csr!("stvec", kernel_entry as u64);
 
     unsafe {
-        virtio_blk::init();
-        tarfs::init();
+        let has_fdt = fdt::parse(PhysAddr::new(dtb)).is_ok();
+        cmdline::init();
+        if has_fdt {
+            fdt::dump();
+        } else {
+            warn!("fdt: device tree not found, using default addresses");
+        }
+        memory::init();
+
+        timer::init();
+        process::init_scheduler(CMDLINE.quantum_ms);
+        rtc::init();
+        plic::init(hartid);
+        uart::init();
+        virtio::init();
+        ramdisk::init();
+        partition::init();
+        vfs::init();
 
         IDLE_PROC = Process::create(ptr::null());
         (*IDLE_PROC).pid = -1;
         CURRENT_PROC = IDLE_PROC;
 
-        let shell = &tarfs::lookup("shell.elf").unwrap().as_ref().unwrap().data as *const u8
-            as *const ElfHeader;
-        Process::create(shell);
-        process_yield();
+        let (init, size) = match vfs::lookup(CMDLINE.init()) {
+            Ok(node) => match vfs::stat_node(node) {
+                Ok(stat) if stat.file_type() == vfs::FileType::Regular => {
+                    (node, stat.size as usize)
+                }
+                _ => panic!("init program is not a regular file: {}", CMDLINE.init()),
+            },
+            Err(_) => panic!("init program not found: {}", CMDLINE.init()),
+        };
+        // ELFイメージ全体をメモリに読み込んでからプロセスを作る
+        let image = alloc_pages(align_up(size as u64, PAGE_SIZE) / PAGE_SIZE);
+        let buf = slice::from_raw_parts_mut(image.as_u64() as *mut u8, size);
+        if vfs::read(init, 0, buf) != Ok(size) {
+            panic!("failed to read init program: {}", CMDLINE.init());
+        }
+        Process::create(image.as_u64() as *const ElfHeader);
+
+        // アイドルプロセス: 実行可能なプロセスがなくなったら割り込みを待つ
+        loop {
+            process_yield();
+            if !process::has_alive() {
+                break;
+            }
+            asm!("csrsi sstatus, 2", "wfi", "csrci sstatus, 2");
+        }
     }
 
     panic!("switched to idle process");
@@ -62,6 +117,7 @@ global_asm!(
 .section ".text.boot"
 .global boot
 boot:
+    // a0: hartid, a1: FDTのアドレス
     la sp, __stack_top
     j  kernel_main
     "#
@@ -1,7 +1,10 @@
-use crate::types::PhysAddr;
+use crate::{fdt::PLATFORM, info, types::PhysAddr};
 use core::{arch::global_asm, ptr};
 
 pub const PAGE_SIZE: u64 = 0x1000;
+/// カーネルが使う空きメモリの上限
+/// (プロセスごとにすべてのページをマッピングするので大きくしすぎない)
+const FREE_RAM_MAX_SIZE: u64 = 1024 * 1024 * 1024; // 1GB
 
 extern "C" {
     static __free_ram: u8;
@@ -9,6 +12,8 @@ extern "C" {
     static mut next_paddr: PhysAddr;
 }
 
+static mut FREE_RAM_END: PhysAddr = PhysAddr::new(0);
+
 global_asm!(
     r#"
 .section ".data"
@@ -22,10 +27,46 @@ pub unsafe fn alloc_pages(n: u64) -> PhysAddr {
     let paddr = next_paddr;
     next_paddr += PhysAddr::new(n * PAGE_SIZE);
 
-    if next_paddr > PhysAddr::new(ptr::addr_of!(__free_ram_end) as u64) {
+    if next_paddr > free_ram_end() {
         panic!("out of memory");
     }
 
     ptr::write_bytes(paddr.as_u64() as *mut u8, 0, (n * PAGE_SIZE) as usize);
     paddr
 }
+
+/// デバイスツリーのメモリ領域から空きメモリの終端を決める
+pub unsafe fn init() {
+    let free_ram = ptr::addr_of!(__free_ram) as u64;
+    let platform = &PLATFORM;
+    for i in 0..platform.memory_len {
+        let mem = platform.memory[i];
+        let start = mem.base.as_u64();
+        let mut end = start + mem.size;
+        if free_ram < start || free_ram >= end {
+            continue;
+        }
+
+        // デバイスツリー自体を上書きしないようにする
+        let dtb = platform.dtb.base.as_u64();
+        if dtb >= free_ram && dtb < end {
+            end = dtb & !(PAGE_SIZE - 1);
+        }
+        end = end.min(free_ram + FREE_RAM_MAX_SIZE);
+
+        FREE_RAM_END = PhysAddr::new(end);
+        info!("memory: free ram is {:#x}-{:#x}", free_ram, end);
+        return;
+    }
+}
+
+/// 空きメモリの終端。デバイスツリーがなければリンカスクリプトの値を使う
+pub fn free_ram_end() -> PhysAddr {
+    unsafe {
+        if FREE_RAM_END.as_u64() != 0 {
+            FREE_RAM_END
+        } else {
+            PhysAddr::new(ptr::addr_of!(__free_ram_end) as u64)
+        }
+    }
+}
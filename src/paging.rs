@@ -1,6 +1,7 @@
 use crate::{
     memory::{alloc_pages, PAGE_SIZE},
     types::{PhysAddr, VirtAddr},
+    utils::align_up,
 };
 
 pub const SATP_SV39: u64 = 8 << 60;
@@ -9,6 +10,12 @@ pub const PAGE_R: u64 = 1 << 1;
 pub const PAGE_W: u64 = 1 << 2;
 pub const PAGE_X: u64 = 1 << 3;
 pub const PAGE_U: u64 = 1 << 4;
+const MMIO_REGIONS_MAX: usize = 16;
+
+/// 全プロセスのページテーブルにマッピングするMMIO領域
+static mut MMIO_REGIONS: [(PhysAddr, u64); MMIO_REGIONS_MAX] =
+    [(PhysAddr::new(0), 0); MMIO_REGIONS_MAX];
+static mut MMIO_REGIONS_LEN: usize = 0;
 
 pub unsafe fn map_page(table2: PhysAddr, vaddr: VirtAddr, paddr: PhysAddr, flags: u64) {
     assert!(vaddr.as_u64() % PAGE_SIZE == 0);
@@ -35,3 +42,31 @@ pub unsafe fn map_page(table2: PhysAddr, vaddr: VirtAddr, paddr: PhysAddr, flags
     let vpn0 = ((vaddr.as_u64() >> 12) & 0b0001_1111_1111) as isize;
     *table0.offset(vpn0) = ((paddr.as_u64() / PAGE_SIZE) << 10) | flags | PAGE_V;
 }
+
+/// デバイスドライバが使うMMIO領域を登録する
+pub unsafe fn register_mmio(paddr: PhysAddr, size: u64) {
+    if MMIO_REGIONS_LEN == MMIO_REGIONS_MAX {
+        panic!("too many mmio regions");
+    }
+    MMIO_REGIONS[MMIO_REGIONS_LEN] = (paddr, size);
+    MMIO_REGIONS_LEN += 1;
+}
+
+/// 登録されたMMIO領域をカーネル用にマッピングする
+pub unsafe fn map_mmio_regions(table2: PhysAddr) {
+    for i in 0..MMIO_REGIONS_LEN {
+        let (paddr, size) = MMIO_REGIONS[i];
+        let start = paddr.as_u64() & !(PAGE_SIZE - 1);
+        let end = align_up(paddr.as_u64() + size, PAGE_SIZE);
+        let mut page = start;
+        while page < end {
+            map_page(
+                table2,
+                VirtAddr::new(page),
+                PhysAddr::new(page),
+                PAGE_R | PAGE_W,
+            );
+            page += PAGE_SIZE;
+        }
+    }
+}
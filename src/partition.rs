@@ -0,0 +1,251 @@
+use crate::{
+    block::{self, BlockDevice, SECTOR_SIZE},
+    info, warn,
+};
+use core::ptr;
+
+const PARTITIONS_MAX: usize = 16;
+const NAME_MAX: usize = 16;
+
+/// MBRのパーティションテーブル
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_ENTRY_NUM: usize = 4;
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_EXTENDED: u8 = 0x05;
+const MBR_TYPE_EXTENDED_LBA: u8 = 0x0f;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+/// GPTのヘッダとパーティションエントリ
+const GPT_HEADER_LBA: u64 = 1;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_HEADER_ENTRY_LBA: usize = 72;
+const GPT_HEADER_ENTRY_NUM: usize = 80;
+const GPT_HEADER_ENTRY_SIZE: usize = 84;
+const GPT_ENTRY_FIRST_LBA: usize = 32;
+const GPT_ENTRY_LAST_LBA: usize = 40;
+/// 大きすぎるテーブルで延々とディスクを読まないようにする
+const GPT_ENTRY_NUM_MAX: u32 = 128;
+
+/// 親デバイスの一部の範囲を1つのブロックデバイスとして見せる
+pub struct Partition {
+    parent: *mut dyn BlockDevice,
+    start: u64,
+    sectors: u64,
+}
+
+impl Partition {
+    /// `sector` から `len` バイトの範囲がパーティションに収まっていれば、親デバイスでのセクタを返す
+    fn to_parent(&self, sector: u64, len: usize) -> Result<u64, ()> {
+        let count = (len / SECTOR_SIZE as usize) as u64;
+        if sector + count > self.sectors {
+            return Err(());
+        }
+        Ok(self.start + sector)
+    }
+}
+
+impl BlockDevice for Partition {
+    fn capacity_sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn block_size(&self) -> u32 {
+        unsafe { (*self.parent).block_size() }
+    }
+
+    fn is_read_only(&self) -> bool {
+        unsafe { (*self.parent).is_read_only() }
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), ()> {
+        let sector = self.to_parent(sector, buf.len())?;
+        unsafe { (*self.parent).read(sector, buf) }
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), ()> {
+        let sector = self.to_parent(sector, buf.len())?;
+        unsafe { (*self.parent).write(sector, buf) }
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        unsafe { (*self.parent).flush() }
+    }
+
+    fn discard(&mut self, sector: u64, count: u64) -> Result<(), ()> {
+        let sector = self.to_parent(sector, (count * SECTOR_SIZE as u64) as usize)?;
+        unsafe { (*self.parent).discard(sector, count) }
+    }
+
+    fn write_zeroes(&mut self, sector: u64, count: u64, unmap: bool) -> Result<(), ()> {
+        let sector = self.to_parent(sector, (count * SECTOR_SIZE as u64) as usize)?;
+        unsafe { (*self.parent).write_zeroes(sector, count, unmap) }
+    }
+}
+
+static mut PARTITIONS: [Option<Partition>; PARTITIONS_MAX] = [const { None }; PARTITIONS_MAX];
+static mut SECTOR_BUF: [u8; SECTOR_SIZE as usize] = [0; SECTOR_SIZE as usize];
+
+fn le32(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..(off + 4)].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..(off + 8)].try_into().unwrap())
+}
+
+/// 登録済みのブロックデバイスのパーティションテーブルを読み、各パーティションを登録する
+pub unsafe fn init() {
+    // ここで登録するパーティション自体は走査しない
+    let n = block::count();
+    for i in 0..n {
+        if let Some((name, dev)) = block::get(i) {
+            scan(name, dev as *mut dyn BlockDevice);
+        }
+    }
+}
+
+unsafe fn scan(name: &str, dev: *mut dyn BlockDevice) {
+    let buf = &mut *ptr::addr_of_mut!(SECTOR_BUF);
+    if (*dev).read(0, buf).is_err() || buf[MBR_SIGNATURE_OFFSET..] != MBR_SIGNATURE {
+        return;
+    }
+
+    // FATなどのブートセクタにも同じシグネチャがあるので、ブートフラグが
+    // 0x00か0x80でなければパーティションテーブルとはみなさない
+    let mut entries = [(0u8, 0u64, 0u64); MBR_ENTRY_NUM];
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let off = MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE;
+        let boot = buf[off];
+        if boot != 0x00 && boot != 0x80 {
+            return;
+        }
+        *entry = (
+            buf[off + 4],
+            le32(buf, off + 8) as u64,
+            le32(buf, off + 12) as u64,
+        );
+    }
+
+    if entries.iter().any(|e| e.0 == MBR_TYPE_GPT_PROTECTIVE) {
+        scan_gpt(name, dev);
+        return;
+    }
+
+    for (i, (type_, start, sectors)) in entries.into_iter().enumerate() {
+        match type_ {
+            MBR_TYPE_EMPTY => continue,
+            // 論理パーティションには対応していない
+            MBR_TYPE_EXTENDED | MBR_TYPE_EXTENDED_LBA => {
+                warn!("partition: {name}: extended partitions are not supported");
+                continue;
+            }
+            _ => add(name, i + 1, dev, start, sectors),
+        }
+    }
+}
+
+unsafe fn scan_gpt(name: &str, dev: *mut dyn BlockDevice) {
+    let buf = &mut *ptr::addr_of_mut!(SECTOR_BUF);
+    if (*dev).read(GPT_HEADER_LBA, buf).is_err() || &buf[0..GPT_SIGNATURE.len()] != GPT_SIGNATURE {
+        warn!("partition: {name}: invalid GPT header");
+        return;
+    }
+
+    let entry_lba = le64(buf, GPT_HEADER_ENTRY_LBA);
+    let entry_num = le32(buf, GPT_HEADER_ENTRY_NUM).min(GPT_ENTRY_NUM_MAX) as usize;
+    let entry_size = le32(buf, GPT_HEADER_ENTRY_SIZE) as usize;
+    if entry_size < 128 || SECTOR_SIZE as usize % entry_size != 0 {
+        warn!("partition: {name}: unsupported GPT entry size {entry_size}");
+        return;
+    }
+
+    // CRC32は確かめずにエントリを順に読む
+    let per_sector = SECTOR_SIZE as usize / entry_size;
+    for i in 0..entry_num {
+        if i % per_sector == 0
+            && (*dev)
+                .read(entry_lba + (i / per_sector) as u64, buf)
+                .is_err()
+        {
+            warn!("partition: {name}: failed to read GPT entries");
+            return;
+        }
+
+        let off = (i % per_sector) * entry_size;
+        // パーティションの種類のGUIDが0なら未使用のエントリ
+        if buf[off..(off + 16)].iter().all(|b| *b == 0) {
+            continue;
+        }
+        let first = le64(buf, off + GPT_ENTRY_FIRST_LBA);
+        let last = le64(buf, off + GPT_ENTRY_LAST_LBA);
+        if last < first {
+            continue;
+        }
+        add(name, i + 1, dev, first, last - first + 1);
+    }
+}
+
+/// `dev` の `start` から `sectors` セクタを `name` にパーティション番号を付けた名前で登録する
+unsafe fn add(name: &str, number: usize, dev: *mut dyn BlockDevice, start: u64, sectors: u64) {
+    if sectors == 0 || start + sectors > (*dev).capacity_sectors() {
+        warn!("partition: {name}: partition {number} is out of range");
+        return;
+    }
+    // ずれていても読み書きはできるが、デバイスによっては遅くなる
+    let block_sectors = ((*dev).block_size() / SECTOR_SIZE).max(1) as u64;
+    if !start.is_multiple_of(block_sectors) {
+        warn!(
+            "partition: {name}: partition {number} is not aligned to the {}-byte block size",
+            (*dev).block_size()
+        );
+    }
+
+    // vda1, ram0p1のように名前を付ける (Linuxと同じ)
+    let mut buf = [0; NAME_MAX];
+    let mut len = name.len();
+    if len + 4 > NAME_MAX {
+        return;
+    }
+    buf[0..len].copy_from_slice(name.as_bytes());
+    if name.ends_with(|c: char| c.is_ascii_digit()) {
+        buf[len] = b'p';
+        len += 1;
+    }
+    let digits = if number >= 100 {
+        3
+    } else if number >= 10 {
+        2
+    } else {
+        1
+    };
+    for i in 0..digits {
+        buf[len + digits - 1 - i] = b'0' + (number / 10usize.pow(i as u32) % 10) as u8;
+    }
+    len += digits;
+    let part_name = core::str::from_utf8(&buf[0..len]).unwrap();
+
+    let partitions = &mut *ptr::addr_of_mut!(PARTITIONS);
+    let slot = match partitions.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => slot,
+        None => {
+            warn!("partition: too many partitions");
+            return;
+        }
+    };
+    *slot = Some(Partition {
+        parent: dev,
+        start,
+        sectors,
+    });
+
+    info!(
+        "partition: {part_name}: sectors {start}-{}",
+        start + sectors - 1
+    );
+    if block::register(part_name, slot.as_mut().unwrap()).is_err() {
+        warn!("partition: failed to register {part_name}");
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{
+    fdt::PLATFORM, info, memory::PAGE_SIZE, paging::register_mmio, read_csr, types::PhysAddr,
+    write_csr,
+};
+
+/// QEMU virtのPLIC
+const DEFAULT_PLIC_PADDR: PhysAddr = PhysAddr::new(0x0c00_0000);
+const PLIC_PRIORITY: u64 = 0x0;
+const PLIC_ENABLE: u64 = 0x2000;
+const PLIC_ENABLE_STRIDE: u64 = 0x80;
+const PLIC_CONTEXT: u64 = 0x20_0000;
+const PLIC_CONTEXT_STRIDE: u64 = 0x1000;
+const PLIC_CONTEXT_THRESHOLD: u64 = 0x0;
+const PLIC_CONTEXT_CLAIM: u64 = 0x4;
+pub const PLIC_IRQS_MAX: u32 = 1024;
+const SIE_SEIE: u64 = 1 << 9;
+
+static mut PLIC_PADDR: PhysAddr = DEFAULT_PLIC_PADDR;
+/// このハートのSモード用のコンテキスト番号
+static mut CONTEXT: u64 = 0;
+
+unsafe fn reg_read32(offset: u64) -> u32 {
+    ((PLIC_PADDR.as_u64() + offset) as *const u32).read_volatile()
+}
+
+unsafe fn reg_write32(offset: u64, value: u32) {
+    ((PLIC_PADDR.as_u64() + offset) as *mut u32).write_volatile(value);
+}
+
+pub unsafe fn init(hartid: u64) {
+    if let Some(plic) = PLATFORM.plic {
+        PLIC_PADDR = plic.base;
+    }
+    // QEMU virtでは各ハートにMモードとSモードのコンテキストが交互に並んでいる
+    CONTEXT = hartid * 2 + 1;
+
+    // 優先度、割り込み許可、コンテキストの各レジスタがあるページだけを登録する
+    register_mmio(
+        PhysAddr::new(PLIC_PADDR.as_u64() + PLIC_PRIORITY),
+        PLIC_IRQS_MAX as u64 * 4,
+    );
+    register_mmio(
+        PhysAddr::new(PLIC_PADDR.as_u64() + enable_offset(0)),
+        PLIC_ENABLE_STRIDE,
+    );
+    register_mmio(
+        PhysAddr::new(PLIC_PADDR.as_u64() + context_offset(0)),
+        PAGE_SIZE,
+    );
+
+    // すべての優先度の割り込みを受け付ける
+    reg_write32(context_offset(PLIC_CONTEXT_THRESHOLD), 0);
+    write_csr!("sie", read_csr!("sie") | SIE_SEIE);
+
+    info!("plic: base={:#x}, context={}", PLIC_PADDR.as_u64(), CONTEXT);
+}
+
+unsafe fn enable_offset(irq: u32) -> u64 {
+    PLIC_ENABLE + CONTEXT * PLIC_ENABLE_STRIDE + (irq / 32) as u64 * 4
+}
+
+unsafe fn context_offset(reg: u64) -> u64 {
+    PLIC_CONTEXT + CONTEXT * PLIC_CONTEXT_STRIDE + reg
+}
+
+/// 割り込み `irq` をこのハートで受け付けるようにする
+pub unsafe fn enable(irq: u32) {
+    reg_write32(PLIC_PRIORITY + irq as u64 * 4, 1);
+    let offset = enable_offset(irq);
+    reg_write32(offset, reg_read32(offset) | (1 << (irq % 32)));
+}
+
+/// 保留中の割り込みを1つ取り出す。なければ0を返す
+pub unsafe fn claim() -> u32 {
+    reg_read32(context_offset(PLIC_CONTEXT_CLAIM))
+}
+
+/// `claim` で取り出した割り込みの処理が終わったことを通知する
+pub unsafe fn complete(irq: u32) {
+    reg_write32(context_offset(PLIC_CONTEXT_CLAIM), irq);
+}
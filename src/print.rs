@@ -1,5 +1,17 @@
 use crate::sbi;
-use core::fmt::Write;
+use core::{fmt::Write, ptr};
+
+pub const LOG_WARN: u8 = 1;
+pub const LOG_INFO: u8 = 2;
+pub const LOG_DEBUG: u8 = 3;
+
+/// この値以下のレベルのログだけを出力する (カーネルコマンドラインの `loglevel=`)
+pub static mut LOG_LEVEL: u8 = LOG_INFO;
+
+/// 現在のログレベル
+pub fn log_level() -> u8 {
+    unsafe { ptr::addr_of!(LOG_LEVEL).read() }
+}
 
 pub struct Console;
 
@@ -28,3 +40,27 @@ macro_rules! println {
     () => ($crate::print!("\r\n"));
     ($($arg:tt)*) => ($crate::print!("{}\r\n", format_args!($($arg)*)));
 }
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $level <= $crate::print::log_level() {
+            $crate::println!($($arg)*);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::print::LOG_WARN, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::print::LOG_INFO, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::print::LOG_DEBUG, $($arg)*));
+}
@@ -1,10 +1,10 @@
 use crate::{
-    __free_ram_end, __kernel_base,
+    __kernel_base,
     elf::ElfHeader,
-    memory::{alloc_pages, PAGE_SIZE},
-    paging::{map_page, PAGE_R, PAGE_U, PAGE_W, PAGE_X, SATP_SV39},
+    memory::{alloc_pages, free_ram_end, PAGE_SIZE},
+    paging::{map_mmio_regions, map_page, PAGE_R, PAGE_U, PAGE_W, PAGE_X, SATP_SV39},
+    timer,
     types::{PhysAddr, VirtAddr},
-    virtio_blk::VIRTIO_BLK_PADDR,
     write_csr,
 };
 use core::{
@@ -21,6 +21,7 @@ const PROCS_MAX: usize = 8;
 pub const PROC_UNUSED: i64 = 0;
 pub const PROC_RUNNABLE: i64 = 1;
 pub const PROC_EXITED: i64 = 2;
+pub const PROC_BLOCKED: i64 = 3;
 
 #[no_mangle]
 pub static USER_BASE: u64 = 0x100_0000;
@@ -36,6 +37,7 @@ pub struct Process {
     pub state: i64,
     pub sp: VirtAddr,
     pub page_table: PhysAddr,
+    pub wait_chan: u64,
     pub stack: [u8; 8192],
 }
 
@@ -46,6 +48,7 @@ impl Process {
             state: PROC_UNUSED,
             sp: VirtAddr::new(0),
             page_table: PhysAddr::new(0),
+            wait_chan: 0,
             stack: [0; 8192],
         }
     }
@@ -90,7 +93,7 @@ impl Process {
 
             // カーネルのページをマッピングする
             let mut paddr = PhysAddr::new(ptr::addr_of!(__kernel_base) as *const u8 as u64);
-            while paddr < PhysAddr::new(ptr::addr_of!(__free_ram_end) as *const u8 as u64) {
+            while paddr < free_ram_end() {
                 map_page(
                     page_table,
                     VirtAddr::new(paddr.as_u64()),
@@ -99,12 +102,7 @@ impl Process {
                 );
                 paddr += PhysAddr::new(PAGE_SIZE);
             }
-            map_page(
-                page_table,
-                VirtAddr::new(VIRTIO_BLK_PADDR.as_u64()),
-                VIRTIO_BLK_PADDR,
-                PAGE_R | PAGE_W,
-            );
+            map_mmio_regions(page_table);
 
             // ユーザーのページをマッピングする
             if image != ptr::null() {
@@ -177,6 +175,38 @@ switch_context:
 
 pub static mut CURRENT_PROC: *mut Process = ptr::null_mut();
 pub static mut IDLE_PROC: *mut Process = ptr::null_mut();
+static mut QUANTUM_TICKS: u64 = 0;
+static mut NEED_RESCHED: bool = false;
+
+/// タイムスライスを `quantum_ms` ミリ秒にしてプリエンプションを有効にする (0なら無効)
+pub unsafe fn init_scheduler(quantum_ms: u64) {
+    if quantum_ms == 0 {
+        return;
+    }
+    QUANTUM_TICKS = timer::ns_to_ticks(quantum_ms.saturating_mul(1_000_000));
+    schedule_tick();
+}
+
+unsafe fn schedule_tick() {
+    if timer::add(timer::now() + QUANTUM_TICKS, tick, 0).is_err() {
+        panic!("failed to arm scheduler tick");
+    }
+}
+
+fn tick(_: u64) {
+    unsafe {
+        NEED_RESCHED = true;
+        schedule_tick();
+    }
+}
+
+/// タイムスライスを使い切っていれば他のプロセスに切り替える
+pub unsafe fn preempt() {
+    if NEED_RESCHED {
+        NEED_RESCHED = false;
+        process_yield();
+    }
+}
 
 pub unsafe fn process_yield() {
     let mut next = IDLE_PROC;
@@ -210,3 +240,38 @@ pub unsafe fn process_yield() {
     CURRENT_PROC = next;
     switch_context(&mut (*prev).sp, &(*next).sp)
 }
+
+/// 現在のコンテキストがプロセスとしてブロックできるか (起動中やアイドルプロセスではできない)
+pub unsafe fn can_block() -> bool {
+    !CURRENT_PROC.is_null() && CURRENT_PROC != IDLE_PROC
+}
+
+/// 現在のプロセスを `chan` で待機状態にして他のプロセスに切り替える
+pub unsafe fn block(chan: u64) {
+    let current = CURRENT_PROC.as_mut().unwrap();
+    current.state = PROC_BLOCKED;
+    current.wait_chan = chan;
+    process_yield();
+}
+
+/// `chan` で待機しているプロセスを実行可能状態に戻す
+pub unsafe fn wakeup(chan: u64) {
+    for i in 0..PROCS_MAX {
+        let proc = &mut PROCS[i];
+        if proc.state == PROC_BLOCKED && proc.wait_chan == chan {
+            proc.state = PROC_RUNNABLE;
+            proc.wait_chan = 0;
+        }
+    }
+}
+
+/// 終了していないプロセスが残っているか
+pub unsafe fn has_alive() -> bool {
+    for i in 0..PROCS_MAX {
+        let proc = &PROCS[i];
+        if proc.pid > 0 && (proc.state == PROC_RUNNABLE || proc.state == PROC_BLOCKED) {
+            return true;
+        }
+    }
+    false
+}
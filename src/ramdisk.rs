@@ -0,0 +1,116 @@
+use crate::{
+    block::{self, BlockDevice, SECTOR_SIZE},
+    memory::{alloc_pages, PAGE_SIZE},
+    utils::align_up,
+    warn,
+};
+use core::{ptr, slice};
+
+const RAMDISKS_MAX: usize = 2;
+
+/// カーネルに埋め込むディスクイメージ (`./run.sh` が作るtarファイル)
+#[cfg(feature = "ramdisk")]
+static IMAGE: &[u8] = include_bytes!("../disk.tar");
+#[cfg(not(feature = "ramdisk"))]
+static IMAGE: &[u8] = &[];
+
+/// メモリ上に確保した領域をディスクとして扱うブロックデバイス
+pub struct RamDisk {
+    data: *mut u8,
+    sectors: u64,
+}
+
+static mut RAMDISKS: [RamDisk; RAMDISKS_MAX] = [const { RamDisk::new() }; RAMDISKS_MAX];
+static mut RAMDISKS_LEN: usize = 0;
+
+impl RamDisk {
+    const fn new() -> Self {
+        Self {
+            data: ptr::null_mut(),
+            sectors: 0,
+        }
+    }
+
+    /// `sector` から `len` バイトの範囲がディスクに収まっていれば、その先頭のオフセットを返す
+    fn range(&self, sector: u64, len: usize) -> Result<usize, ()> {
+        if len % SECTOR_SIZE as usize != 0 {
+            return Err(());
+        }
+        let count = (len / SECTOR_SIZE as usize) as u64;
+        if sector + count > self.sectors {
+            return Err(());
+        }
+        Ok((sector * SECTOR_SIZE as u64) as usize)
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn capacity_sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn block_size(&self) -> u32 {
+        SECTOR_SIZE
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), ()> {
+        let off = self.range(sector, buf.len())?;
+        unsafe { ptr::copy_nonoverlapping(self.data.add(off), buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), ()> {
+        let off = self.range(sector, buf.len())?;
+        unsafe { ptr::copy_nonoverlapping(buf.as_ptr(), self.data.add(off), buf.len()) };
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    /// メモリは解放できないので、内容を0にするだけ
+    fn discard(&mut self, sector: u64, count: u64) -> Result<(), ()> {
+        self.write_zeroes(sector, count, true)
+    }
+
+    fn write_zeroes(&mut self, sector: u64, count: u64, _unmap: bool) -> Result<(), ()> {
+        let len = (count * SECTOR_SIZE as u64) as usize;
+        let off = self.range(sector, len)?;
+        unsafe { ptr::write_bytes(self.data.add(off), 0, len) };
+        Ok(())
+    }
+}
+
+/// `size` バイトのRAMディスクを作り、`image` で初期化して `name` で登録する
+pub unsafe fn create(name: &str, size: u64, image: &[u8]) -> Result<(), ()> {
+    if RAMDISKS_LEN == RAMDISKS_MAX || (image.len() as u64) > size {
+        return Err(());
+    }
+
+    let size = align_up(size, PAGE_SIZE);
+    let data = alloc_pages(size / PAGE_SIZE).as_u64() as *mut u8;
+    slice::from_raw_parts_mut(data, image.len()).copy_from_slice(image);
+
+    let index = RAMDISKS_LEN;
+    RAMDISKS[index] = RamDisk {
+        data,
+        sectors: size / SECTOR_SIZE as u64,
+    };
+    RAMDISKS_LEN += 1;
+    block::register(name, &mut *ptr::addr_of_mut!(RAMDISKS[index]))
+}
+
+/// 埋め込まれたディスクイメージがあれば `ram0` として登録する
+pub unsafe fn init() {
+    if IMAGE.is_empty() {
+        return;
+    }
+    if create("ram0", IMAGE.len() as u64, IMAGE).is_err() {
+        warn!("ramdisk: failed to create ram0");
+    }
+}
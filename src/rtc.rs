@@ -0,0 +1,36 @@
+use crate::{fdt::PLATFORM, info, paging::register_mmio, types::PhysAddr};
+
+/// QEMU virtのGoldfish RTC
+const DEFAULT_RTC_PADDR: PhysAddr = PhysAddr::new(0x10_1000);
+const RTC_TIME_LOW: u64 = 0x00;
+const RTC_TIME_HIGH: u64 = 0x04;
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+static mut RTC_PADDR: PhysAddr = DEFAULT_RTC_PADDR;
+
+unsafe fn reg_read32(offset: u64) -> u32 {
+    ((RTC_PADDR.as_u64() + offset) as *const u32).read_volatile()
+}
+
+pub unsafe fn init() {
+    if let Some(rtc) = PLATFORM.rtc {
+        RTC_PADDR = rtc.base;
+    }
+    register_mmio(RTC_PADDR, 0x1000);
+    info!("rtc: unix time is {}", now_secs());
+}
+
+/// UNIXエポックからの経過ナノ秒
+pub fn now_ns() -> u64 {
+    unsafe {
+        // TIME_LOWを読むとTIME_HIGHの値がラッチされるので、必ずLOWから読む
+        let low = reg_read32(RTC_TIME_LOW) as u64;
+        let high = reg_read32(RTC_TIME_HIGH) as u64;
+        (high << 32) | low
+    }
+}
+
+/// UNIXエポックからの経過秒
+pub fn now_secs() -> u64 {
+    now_ns() / NSEC_PER_SEC
+}
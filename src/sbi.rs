@@ -1,5 +1,6 @@
 use core::arch::asm;
 
+const EID_SET_TIMER: i64 = 0x00;
 const EID_CONSOLE_PUTCHAR: i64 = 0x01;
 
 pub struct SbiRet {
@@ -38,3 +39,9 @@ pub fn getchar() -> i64 {
     let ret = unsafe { sbi_call(0, 0, 0, 0, 0, 0, 0, 2) };
     ret.error
 }
+
+pub fn set_timer(stime_value: u64) {
+    unsafe {
+        sbi_call(stime_value as i64, 0, 0, 0, 0, 0, 0, EID_SET_TIMER);
+    }
+}
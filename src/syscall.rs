@@ -2,17 +2,47 @@ use crate::{
     handler::TrapFrame,
     println,
     process::{process_yield, CURRENT_PROC, PROC_EXITED},
+    rtc,
     sbi::{getchar, putchar},
-    tarfs,
+    timer::{self, Timespec},
+    uart,
     utils::ascii_len,
+    vfs::{self, FileType, Stat},
 };
-use core::{mem, slice};
+use core::slice;
 
 const SYS_PUTCHAR: u64 = 1;
 const SYS_GETCHAR: u64 = 2;
 const SYS_EXIT: u64 = 3;
 const SYS_READFILE: u64 = 4;
 const SYS_WRITEFILE: u64 = 5;
+const SYS_SLEEP: u64 = 6;
+const SYS_NANOSLEEP: u64 = 7;
+const SYS_CLOCK_GETTIME: u64 = 8;
+const SYS_MKDIR: u64 = 9;
+const SYS_RMDIR: u64 = 10;
+const SYS_READDIR: u64 = 11;
+const SYS_OPEN: u64 = 12;
+const SYS_UNLINK: u64 = 13;
+const SYS_RENAME: u64 = 14;
+const SYS_STAT: u64 = 15;
+const SYS_LSTAT: u64 = 16;
+const SYS_SYNC: u64 = 17;
+const SYS_FSYNC: u64 = 18;
+const SYS_MOUNT: u64 = 19;
+
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_MONOTONIC: u64 = 1;
+
+const O_CREAT: u64 = 0o100;
+const O_TRUNC: u64 = 0o1000;
+
+/// ユーザーが渡したNUL終端の文字列
+unsafe fn user_str(ptr: u64) -> &'static str {
+    let ptr = ptr as *const u8;
+    let len = ascii_len(ptr);
+    core::str::from_utf8(slice::from_raw_parts(ptr, len - 1)).unwrap_or("")
+}
 
 pub fn handle_syscall(f: *mut TrapFrame) {
     let f = unsafe { f.as_mut().unwrap() };
@@ -21,6 +51,9 @@ pub fn handle_syscall(f: *mut TrapFrame) {
         SYS_PUTCHAR => {
             putchar(f.a0 as u8);
         }
+        SYS_GETCHAR if uart::is_enabled() => {
+            f.a0 = unsafe { uart::getchar() } as u64;
+        }
         SYS_GETCHAR => loop {
             let ch = getchar();
             if ch >= 0 {
@@ -29,6 +62,8 @@ pub fn handle_syscall(f: *mut TrapFrame) {
             }
 
             unsafe {
+                // SBIのgetcharはポーリングなので、その間にタイマーの期限を確認する
+                timer::run_expired();
                 process_yield();
             }
         },
@@ -42,36 +77,171 @@ pub fn handle_syscall(f: *mut TrapFrame) {
             unreachable!();
         }
         SYS_READFILE | SYS_WRITEFILE => {
-            let filename = f.a0 as *const u8;
-            let filename_len = ascii_len(filename);
-            let filename = unsafe {
-                core::str::from_utf8(slice::from_raw_parts(filename, filename_len - 1)).unwrap()
-            };
+            let filename = unsafe { user_str(f.a0) };
             let buf = f.a1 as *mut u8;
-            let mut len = f.a2 as usize;
-            let file = if let Ok(f) = tarfs::lookup(filename) {
-                unsafe { f.as_mut().unwrap() }
+            let len = f.a2 as usize;
+            let file = if let Ok(node) = unsafe { vfs::lookup(filename) } {
+                node
             } else {
                 println!("file not found: {}", filename);
                 f.a0 = 0xffff_ffff_ffff_fffe as u64;
                 return;
             };
 
-            if len > mem::size_of_val(&file.data) {
-                len = file.size;
-            }
             let buf = unsafe { slice::from_raw_parts_mut(buf, len) };
 
-            if sysno == SYS_WRITEFILE {
-                file.data[0..buf.len()].copy_from_slice(buf);
-                file.size = len;
-                unsafe { tarfs::flush() };
+            let result = if sysno == SYS_WRITEFILE {
+                // 内容を置き換えて、書き込んだファイルの変更だけを書き戻す
+                unsafe {
+                    vfs::truncate(file, len)
+                        .and_then(|_| vfs::write(file, 0, buf))
+                        .and_then(|_| vfs::fsync(file))
+                        .map(|_| len)
+                }
             } else {
-                buf.copy_from_slice(&file.data[0..len]);
+                unsafe { vfs::read(file, 0, buf) }
+            };
+
+            f.a0 = match result {
+                Ok(len) => len as u64,
+                Err(_) => -1i64 as u64,
+            };
+        }
+        SYS_SLEEP => {
+            unsafe { timer::sleep(f.a0.saturating_mul(1_000_000_000)) };
+            f.a0 = 0;
+        }
+        SYS_NANOSLEEP => {
+            let Some(req) = (unsafe { (f.a0 as *const Timespec).as_ref() }) else {
+                f.a0 = -1i64 as u64;
+                return;
+            };
+            match req.to_ns() {
+                Ok(ns) => {
+                    unsafe { timer::sleep(ns) };
+                    f.a0 = 0;
+                }
+                Err(_) => f.a0 = -1i64 as u64,
             }
+        }
+        SYS_CLOCK_GETTIME => {
+            let ns = match f.a0 {
+                CLOCK_REALTIME => rtc::now_ns(),
+                CLOCK_MONOTONIC => timer::ticks_to_ns(timer::now()),
+                _ => {
+                    f.a0 = -1i64 as u64;
+                    return;
+                }
+            };
+            let Some(tp) = (unsafe { (f.a1 as *mut Timespec).as_mut() }) else {
+                f.a0 = -1i64 as u64;
+                return;
+            };
+            *tp = Timespec::from_ns(ns);
+            f.a0 = 0;
+        }
+        SYS_MKDIR | SYS_RMDIR => {
+            let path = unsafe { user_str(f.a0) };
+            let result = unsafe {
+                if sysno == SYS_MKDIR {
+                    vfs::mkdir(path)
+                } else {
+                    vfs::rmdir(path)
+                }
+            };
+            f.a0 = match result.and_then(|_| unsafe { vfs::sync() }) {
+                Ok(_) => 0,
+                Err(_) => -1i64 as u64,
+            };
+        }
+        SYS_OPEN | SYS_UNLINK | SYS_RENAME => {
+            let path = unsafe { user_str(f.a0) };
+            let result = unsafe {
+                match sysno {
+                    // ファイルディスクリプタはまだないので、ファイルがあるか作れたかだけを返す
+                    SYS_OPEN => match vfs::lookup(path) {
+                        Ok(file) if f.a1 & O_TRUNC != 0 => vfs::truncate(file, 0),
+                        Ok(_) => Ok(()),
+                        Err(_) if f.a1 & O_CREAT != 0 => vfs::create(path).map(|_| ()),
+                        Err(_) => Err(()),
+                    },
+                    SYS_UNLINK => vfs::unlink(path),
+                    _ => vfs::rename(path, user_str(f.a1)),
+                }
+            };
+            f.a0 = match result.and_then(|_| unsafe { vfs::sync() }) {
+                Ok(_) => 0,
+                Err(_) => -1i64 as u64,
+            };
+        }
+        SYS_READDIR => {
+            // 直下のエントリの名前をNUL区切りで詰める (ディレクトリには'/'、シンボリックリンクには'@'を付ける)
+            let path = unsafe { user_str(f.a0) };
+            let buf = unsafe { slice::from_raw_parts_mut(f.a1 as *mut u8, f.a2 as usize) };
 
+            let mut len = 0;
+            for index in 0.. {
+                let entry = match unsafe { vfs::readdir(path, index) } {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => break,
+                    Err(_) => {
+                        f.a0 = -1i64 as u64;
+                        return;
+                    }
+                };
+                let name = entry.name();
+                let suffix: &[u8] = match entry.kind {
+                    FileType::Directory => b"/\0",
+                    FileType::Symlink => b"@\0",
+                    FileType::Regular => b"\0",
+                };
+                // 入りきらない分は返さない
+                if len + name.len() + suffix.len() > buf.len() {
+                    break;
+                }
+                buf[len..(len + name.len())].copy_from_slice(name);
+                len += name.len();
+                buf[len..(len + suffix.len())].copy_from_slice(suffix);
+                len += suffix.len();
+            }
             f.a0 = len as u64;
         }
+        SYS_STAT | SYS_LSTAT => {
+            let path = unsafe { user_str(f.a0) };
+            match unsafe { vfs::stat(path, sysno == SYS_STAT) } {
+                Ok(stat) => match unsafe { (f.a1 as *mut Stat).as_mut() } {
+                    Some(st) => {
+                        *st = stat;
+                        f.a0 = 0;
+                    }
+                    None => f.a0 = -1i64 as u64,
+                },
+                Err(_) => f.a0 = -1i64 as u64,
+            }
+        }
+        SYS_SYNC => {
+            f.a0 = match unsafe { vfs::sync() } {
+                Ok(_) => 0,
+                Err(_) => -1i64 as u64,
+            };
+        }
+        SYS_FSYNC => {
+            // ファイルディスクリプタはまだないのでパスで指定する
+            let path = unsafe { user_str(f.a0) };
+            let result = unsafe { vfs::lookup(path).and_then(|file| vfs::fsync(file)) };
+            f.a0 = match result {
+                Ok(_) => 0,
+                Err(_) => -1i64 as u64,
+            };
+        }
+        SYS_MOUNT => {
+            // ファイルシステムの種類は中身から判断する
+            let (dev, path) = unsafe { (user_str(f.a0), user_str(f.a1)) };
+            f.a0 = match unsafe { vfs::mount_device(dev, path, "") } {
+                Ok(_) => 0,
+                Err(_) => -1i64 as u64,
+            };
+        }
         _ => panic!("unexpected syscall a3={:x}", sysno),
     }
 }
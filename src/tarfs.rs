@@ -1,16 +1,23 @@
 use crate::{
-    println,
-    utils::{align_up, ascii_len, oct2int},
-    virtio_blk::{self, read_write_disk, SECTOR_SIZE},
+    bcache,
+    block::{BlockDevice, SECTOR_SIZE},
+    info,
+    memory::{alloc_pages, PAGE_SIZE},
+    rtc,
+    utils::{align_up, int2oct, oct2int},
+    vfs::{DirEntry, FileSystem, FileType, Ino, Stat, PATH_MAX, S_IFDIR, S_IFLNK, S_IFREG},
+    warn,
 };
-use core::{mem, slice};
+use core::{mem, ptr, slice};
 
-const FILES_MAX: usize = 3;
-const FILE_DATA_MAX: usize = 64 * 1024; // 64KB
-const DISK_MAX_SIZE: usize = align_up(
-    (mem::size_of::<File>() * FILES_MAX) as u64,
-    SECTOR_SIZE as u64,
-) as usize;
+/// 1ページに収まるだけのファイルエントリをまとめて確保する
+const FILES_PER_CHUNK: usize =
+    (PAGE_SIZE as usize - mem::size_of::<*mut FileChunk>()) / mem::size_of::<File>();
+const HEADER_SIZE: u64 = mem::size_of::<TarHeader>() as u64;
+/// アーカイブの終端を示す空のブロックの数
+const END_BLOCKS: u64 = 2;
+/// GNUの長い名前やpaxの拡張ヘッダの内容として読み込める最大のサイズ
+const EXT_DATA_MAX: usize = 4096;
 
 #[repr(C, packed)]
 #[derive(Debug)]
@@ -35,174 +42,1369 @@ struct TarHeader {
     pub data: [u8; 0],
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Regular,
+    Directory,
+    Symlink,
+    /// アーカイブ中の別のファイルと内容を共有するハードリンク
+    HardLink,
+}
+
+/// ユーザー名やグループ名のフィールドの長さ
+const OWNER_NAME_LEN: usize = 32;
+
+/// アーカイブ中のファイルの情報 (内容はディスクから必要なときに読む)
 #[derive(Debug, Clone, Copy)]
 pub struct File {
-    pub in_use: bool,              // このファイルエントリが使われているか
-    pub name: [u8; 100],           // ファイル名
-    pub data: [u8; FILE_DATA_MAX], // ファイルの内容
-    pub size: usize,               // ファイルサイズ
+    pub in_use: bool,         // このファイルエントリが使われているか
+    pub name: [u8; PATH_MAX], // 先頭と末尾の'/'を除いたパス
+    pub kind: FileKind,
+    pub size: usize, // ファイルサイズ
+    pub mtime: u64,  // 最終更新時刻 (UNIX時間)
+    pub mode: u32,   // パーミッション (種類のビットは含まない)
+    pub uid: u32,
+    pub gid: u32,
+    pub uname: [u8; OWNER_NAME_LEN],
+    pub gname: [u8; OWNER_NAME_LEN],
+    pub link: [u8; PATH_MAX], // シンボリックリンクやハードリンクの指す先
+    dirty: bool,              // ヘッダや内容をまだディスクに書き戻していないか
+    implicit: bool, // アーカイブにヘッダがなく、中のエントリのパスから補ったディレクトリか
+    header_off: u64, // 拡張ヘッダも含めたヘッダのディスク上のオフセット (バイト)
+    ext_len: u64,   // ustarヘッダの前にあるGNUやpaxの拡張ヘッダのサイズ
 }
 
 impl File {
     const fn new() -> Self {
         Self {
             in_use: false,
-            name: [0; 100],
-            data: [0; FILE_DATA_MAX],
+            name: [0; PATH_MAX],
+            kind: FileKind::Regular,
             size: 0,
+            mtime: 0,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            uname: [0; OWNER_NAME_LEN],
+            gname: [0; OWNER_NAME_LEN],
+            link: [0; PATH_MAX],
+            dirty: false,
+            implicit: false,
+            header_off: 0,
+            ext_len: 0,
+        }
+    }
+
+    /// シンボリックリンクやハードリンクの指す先
+    pub fn link(&self) -> &str {
+        core::str::from_utf8(field_str(&self.link)).unwrap_or("")
+    }
+
+    /// アーカイブのルートからのパス
+    pub fn path(&self) -> &str {
+        core::str::from_utf8(field_str(&self.name)).unwrap_or("")
+    }
+
+    /// パスの最後の要素
+    pub fn basename(&self) -> &str {
+        let path = self.path();
+        match path.rfind('/') {
+            Some(i) => &path[(i + 1)..],
+            None => path,
         }
     }
+
+    fn data_off(&self) -> u64 {
+        self.header_off + self.ext_len + HEADER_SIZE
+    }
+
+    /// ヘッダからファイルデータの最後のセクタの終わりまで
+    fn end_off(&self) -> u64 {
+        self.data_off() + padded_size(self.size)
+    }
 }
 
-static mut FILES: [File; FILES_MAX] = [File::new(); FILES_MAX];
-static mut DISK: [u8; DISK_MAX_SIZE] = [0; DISK_MAX_SIZE];
+/// ファイルエントリをページ単位で確保してつなげたリスト
+struct FileChunk {
+    files: [File; FILES_PER_CHUNK],
+    next: *mut FileChunk,
+}
+
+static mut FILES: *mut FileChunk = ptr::null_mut();
+static mut DEV: Option<*mut dyn BlockDevice> = None;
+/// 最後のエントリの終わり (終端の空のブロックの先頭) のオフセット
+static mut ARCHIVE_END: u64 = 0;
+/// エントリの移動や追加でレイアウトが変わった範囲の先頭 (ここから終端までを書き戻す必要がある)
+static mut RELAYOUT_FROM: Option<u64> = None;
+/// ファイルの最後のセクタの余りやアーカイブの終端を埋めるための0のセクタ
+static ZERO_SECTOR: [u8; SECTOR_SIZE as usize] = [0; SECTOR_SIZE as usize];
+/// アーカイブの一部を移動するときに使うバッファ
+static mut MOVE_BUF: [u8; SECTOR_SIZE as usize] = [0; SECTOR_SIZE as usize];
+/// 拡張ヘッダの内容を読み込むバッファ
+static mut EXT_BUF: [u8; EXT_DATA_MAX] = [0; EXT_DATA_MAX];
+
+/// ファイルシステムを置くブロックデバイス
+unsafe fn disk() -> *mut dyn BlockDevice {
+    match DEV {
+        Some(dev) => dev,
+        None => panic!("tarfs: not mounted"),
+    }
+}
 
-pub unsafe fn init() {
-    let mut sector = 0;
-    while sector < mem::size_of_val(&DISK) / SECTOR_SIZE as usize {
-        if let Err(_) = virtio_blk::read_write_disk(
-            &mut DISK[sector * SECTOR_SIZE as usize],
-            sector as u32,
-            false,
-        ) {
+fn header_bytes(header: &mut TarHeader) -> &mut [u8] {
+    unsafe {
+        slice::from_raw_parts_mut(
+            header as *mut TarHeader as *mut u8,
+            mem::size_of::<TarHeader>(),
+        )
+    }
+}
+
+/// ヘッダのチェックサム (チェックサムのフィールドを空白とみなした全バイトの和)
+fn checksum(header: &TarHeader) -> u32 {
+    let bytes = unsafe {
+        slice::from_raw_parts(
+            header as *const TarHeader as *const u8,
+            mem::size_of::<TarHeader>(),
+        )
+    };
+    let start = mem::offset_of!(TarHeader, checksum);
+    let end = start + mem::size_of_val(&header.checksum);
+    bytes.iter().enumerate().fold(0, |sum, (i, b)| {
+        if (start..end).contains(&i) {
+            sum + b' ' as u32
+        } else {
+            sum + *b as u32
+        }
+    })
+}
+
+/// ヘッダの数値のフィールドの値
+///
+/// 8進数に収まらない値は、GNU tarと同じく先頭のバイトの最上位ビットを立てた
+/// ビッグエンディアンの2進数 (base-256) で入っている
+fn num_field(field: &[u8]) -> u64 {
+    if field[0] & 0x80 == 0 {
+        return oct2int(field.as_ptr(), field.len());
+    }
+    // 負の値は使わないので0とみなし、u64に収まらない値は最大値にする
+    if field[0] & 0x40 != 0 {
+        return 0;
+    }
+    field[1..]
+        .iter()
+        .try_fold((field[0] & 0x3f) as u64, |n, b| {
+            n.checked_mul(256).map(|n| n | *b as u64)
+        })
+        .unwrap_or(u64::MAX)
+}
+
+/// ファイルの内容が入っているセクタの後ろの余りも含めたサイズ
+fn padded_size(size: usize) -> u64 {
+    align_up(size as u64, SECTOR_SIZE as u64)
+}
+
+/// NUL終端されているかもしれないヘッダのフィールド
+fn field_str(field: &[u8]) -> &[u8] {
+    let len = field.iter().position(|c| *c == 0).unwrap_or(field.len());
+    &field[0..len]
+}
+
+/// 先頭の "/" や "./" と末尾の "/" を取り除く
+fn normalize(mut path: &str) -> &str {
+    loop {
+        if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else {
             break;
         }
-        sector += 1;
     }
+    if path == "." {
+        return "";
+    }
+    path.trim_end_matches('/')
+}
+
+/// パスをヘッダのprefixとnameに分ける。ustarで表せなければ `None` を返す
+fn split_path(path: &str, kind: FileKind) -> Option<(&str, &str)> {
+    // ディレクトリは末尾に'/'を付けるので1バイト余分に必要
+    let name_max = if kind == FileKind::Directory { 99 } else { 100 };
+    if path.len() <= name_max {
+        return Some(("", path));
+    }
+    // nameが収まる位置にある'/'のうち、最も前のもので分ける
+    let min = path.len().saturating_sub(name_max + 1);
+    let i = path[min..].find('/')? + min;
+    if i > 155 || i + 1 == path.len() {
+        return None;
+    }
+    Some((&path[0..i], &path[(i + 1)..]))
+}
+
+/// `path` の親ディレクトリのパス (ルートなら空文字列)
+fn parent_of(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(i) => &path[0..i],
+        None => "",
+    }
+}
+
+/// すべてのファイルエントリを順にたどる
+struct FileIter {
+    chunk: *mut FileChunk,
+    index: usize,
+}
+
+impl Iterator for FileIter {
+    type Item = &'static mut File;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            while !self.chunk.is_null() {
+                if self.index == FILES_PER_CHUNK {
+                    self.chunk = (*self.chunk).next;
+                    self.index = 0;
+                    continue;
+                }
+                let file = &mut (*self.chunk).files[self.index];
+                self.index += 1;
+                if file.in_use {
+                    return Some(file);
+                }
+            }
+            None
+        }
+    }
+}
+
+unsafe fn files() -> FileIter {
+    FileIter {
+        chunk: FILES,
+        index: 0,
+    }
+}
 
+/// 空いているファイルエントリを探す。なければ新しいページを確保する
+unsafe fn alloc_file() -> &'static mut File {
+    let mut chunk = FILES;
+    while !chunk.is_null() {
+        if let Some(file) = (*chunk).files.iter_mut().find(|file| !file.in_use) {
+            return file;
+        }
+        chunk = (*chunk).next;
+    }
+
+    let pages = align_up(mem::size_of::<FileChunk>() as u64, PAGE_SIZE) / PAGE_SIZE;
+    let chunk = alloc_pages(pages).as_u64() as *mut FileChunk;
+    (*chunk).next = FILES;
+    FILES = chunk;
+    &mut (*chunk).files[0]
+}
+
+/// GNUやpaxの拡張ヘッダで指定され、次のエントリに適用される値
+#[derive(Clone, Copy)]
+struct Extension {
+    path: Option<[u8; PATH_MAX]>,
+    link: Option<[u8; PATH_MAX]>,
+    size: Option<usize>,
+    mtime: Option<u64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    uname: Option<[u8; OWNER_NAME_LEN]>,
+    gname: Option<[u8; OWNER_NAME_LEN]>,
+}
+
+impl Extension {
+    const fn new() -> Self {
+        Self {
+            path: None,
+            link: None,
+            size: None,
+            mtime: None,
+            uid: None,
+            gid: None,
+            uname: None,
+            gname: None,
+        }
+    }
+
+    /// `other` で指定された値で上書きする
+    fn merge(&mut self, other: &Extension) {
+        self.path = other.path.or(self.path);
+        self.link = other.link.or(self.link);
+        self.size = other.size.or(self.size);
+        self.mtime = other.mtime.or(self.mtime);
+        self.uid = other.uid.or(self.uid);
+        self.gid = other.gid.or(self.gid);
+        self.uname = other.uname.or(self.uname);
+        self.gname = other.gname.or(self.gname);
+    }
+
+    /// パスを固定長のバッファに入れる (NUL終端が入らない長さなら `None`)
+    fn path_buf(path: &[u8]) -> Option<[u8; PATH_MAX]> {
+        if path.len() >= PATH_MAX {
+            warn!("tarfs: path is too long ({} bytes)", path.len());
+            return None;
+        }
+        let mut buf = [0; PATH_MAX];
+        buf[0..path.len()].copy_from_slice(path);
+        Some(buf)
+    }
+
+    /// 長すぎるユーザー名やグループ名は切り詰める
+    fn owner_name(name: &[u8]) -> [u8; OWNER_NAME_LEN] {
+        let mut buf = [0; OWNER_NAME_LEN];
+        let len = name.len().min(OWNER_NAME_LEN - 1);
+        buf[0..len].copy_from_slice(&name[0..len]);
+        buf
+    }
+
+    fn set_path(&mut self, path: &[u8]) {
+        self.path = Self::path_buf(path).or(self.path);
+    }
+
+    fn set_link(&mut self, link: &[u8]) {
+        self.link = Self::path_buf(link).or(self.link);
+    }
+
+    /// paxの拡張ヘッダの "<長さ> <キー>=<値>\n" というレコードを読む
+    fn parse_pax(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let Some(space) = data.iter().position(|c| *c == b' ') else {
+                break;
+            };
+            let len = match parse_decimal(&data[0..space]) {
+                Some(len) if len > space + 1 && len <= data.len() => len,
+                _ => {
+                    warn!("tarfs: malformed pax record");
+                    break;
+                }
+            };
+            let record = &data[(space + 1)..(len - 1)];
+            data = &data[len..];
+
+            let Some(eq) = record.iter().position(|c| *c == b'=') else {
+                continue;
+            };
+            let (key, value) = (&record[0..eq], &record[(eq + 1)..]);
+            match key {
+                b"path" => self.set_path(value),
+                b"linkpath" => self.set_link(value),
+                b"size" => self.size = parse_decimal(value),
+                b"uid" => self.uid = parse_decimal(value).map(|id| id as u32),
+                b"gid" => self.gid = parse_decimal(value).map(|id| id as u32),
+                b"uname" => self.uname = Some(Self::owner_name(value)),
+                b"gname" => self.gname = Some(Self::owner_name(value)),
+                // 秒未満の部分は捨てる
+                b"mtime" => {
+                    let secs = value.split(|c| *c == b'.').next().unwrap_or(value);
+                    self.mtime = parse_decimal(secs).map(|t| t as u64);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn parse_decimal(s: &[u8]) -> Option<usize> {
+    if s.is_empty() {
+        return None;
+    }
+    s.iter().try_fold(0usize, |n, c| {
+        if c.is_ascii_digit() {
+            n.checked_mul(10)?.checked_add((c - b'0') as usize)
+        } else {
+            None
+        }
+    })
+}
+
+/// `off` にあるエントリの内容を拡張ヘッダとして読む。大きすぎれば `None` を返す
+unsafe fn read_ext_data(off: u64, size: usize) -> Result<Option<&'static [u8]>, ()> {
+    if size > EXT_DATA_MAX {
+        return Ok(None);
+    }
+    let buf = &mut *ptr::addr_of_mut!(EXT_BUF);
+    let buf = &mut buf[0..size];
+    bcache::read(disk(), off + HEADER_SIZE, buf)?;
+    Ok(Some(buf))
+}
+
+/// `dev` のtarアーカイブを読み込んでファイルシステムとして返す
+pub unsafe fn mount(dev: *mut dyn BlockDevice) -> Result<&'static mut dyn FileSystem, ()> {
+    if DEV.is_some() {
+        warn!("tarfs: already mounted");
+        return Err(());
+    }
+
+    DEV = Some(dev);
+    if scan().is_err() {
+        // 途中まで読んだエントリは捨てる
+        for file in files() {
+            file.in_use = false;
+        }
+        DEV = None;
+        return Err(());
+    }
+    Ok(&mut *ptr::addr_of_mut!(TARFS))
+}
+
+/// アーカイブのヘッダを順に読んでファイルエントリを作る
+unsafe fn scan() -> Result<(), ()> {
+    let capacity = (*disk()).capacity_sectors() * SECTOR_SIZE as u64;
     let mut off = 0;
-    for i in 0..FILES_MAX {
-        let header = (&mut DISK[off] as *mut u8 as *mut TarHeader)
-            .as_mut()
-            .unwrap();
-
-        let name = &core::str::from_utf8(&header.name).unwrap()
-            [0..(ascii_len(&header.name as *const u8) - 1)];
-        if name.is_empty() {
+    // paxのグローバルヘッダ (`g`) の値と、次のエントリだけに適用する値
+    let mut global = Extension::new();
+    let mut pending = Extension::new();
+    // 次のエントリの拡張ヘッダの始まり
+    let mut entry_off = None;
+    while off + HEADER_SIZE <= capacity {
+        let mut header: TarHeader = mem::zeroed();
+        if bcache::read(disk(), off, header_bytes(&mut header)).is_err() {
+            warn!("tarfs: failed to read disk");
+            return Err(());
+        }
+        let header = &header;
+
+        if header.name[0] == 0 {
+            // 先頭が0でもパーティションテーブルなどが入っているデバイスを空のアーカイブとして
+            // 上書きしないように、空のアーカイブは終端のブロックがそろっているものだけにする
+            if off == 0 && !is_end_blocks(off)? {
+                warn!("tarfs: no tar archive found");
+                return Err(());
+            }
             break;
         }
 
-        let magic = &core::str::from_utf8(&header.magic).unwrap()
-            [0..(ascii_len(&header.magic as *const u8) - 1)];
-        if magic != "ustar" {
-            panic!("invalid tar header: magic=\"{magic}\"");
+        // POSIXのustarは "ustar\0"、GNU tarは "ustar " になっている
+        let gnu = header.magic == *b"ustar ";
+        if header.magic != *b"ustar\0" && !gnu {
+            let magic = core::str::from_utf8(field_str(&header.magic)).unwrap_or("?");
+            warn!("tarfs: invalid tar header at offset {off}: magic=\"{magic}\"");
+            return Err(());
+        }
+
+        // 古いtarは先頭を空白で埋めていることがある
+        let stored = header.checksum.iter().position(|c| *c != b' ').unwrap_or(0);
+        let stored = oct2int(
+            &header.checksum[stored] as *const u8,
+            mem::size_of_val(&header.checksum) - stored,
+        );
+        let computed = checksum(header) as u64;
+        if stored != computed {
+            let name = core::str::from_utf8(field_str(&header.name)).unwrap_or("?");
+            warn!(
+                "tarfs: checksum mismatch at offset {off} ({name}): stored={stored:o}, computed={computed:o}"
+            );
+            return Err(());
+        }
+
+        let header_size = num_field(&header.size);
+        if header_size > capacity {
+            warn!("tarfs: archive is truncated");
+            return Err(());
+        }
+        let header_size = header_size as usize;
+
+        // 拡張ヘッダは次のエントリに適用して、それ自体は索引に載せない
+        match header.type_ {
+            b'L' | b'K' | b'x' | b'g' => {
+                let data = match read_ext_data(off, header_size)? {
+                    Some(data) => data,
+                    None => {
+                        warn!("tarfs: extended header at offset {off} is too large");
+                        &[]
+                    }
+                };
+                match header.type_ {
+                    b'L' => pending.set_path(field_str(data)),
+                    b'K' => pending.set_link(field_str(data)),
+                    b'x' => pending.parse_pax(data),
+                    _ => global.parse_pax(data),
+                }
+                if header.type_ != b'g' && entry_off.is_none() {
+                    entry_off = Some(off);
+                }
+                off += HEADER_SIZE + padded_size(header_size);
+                continue;
+            }
+            _ => {}
         }
 
-        let filesz = oct2int(
-            &header.size as *const [u8] as *const u8,
-            mem::size_of_val(&header.size),
-        ) as usize;
+        let mut ext = global;
+        ext.merge(&pending);
+        let filesz = ext.size.unwrap_or(header_size);
+        if filesz as u64 > capacity {
+            warn!("tarfs: archive is truncated");
+            return Err(());
+        }
+
+        // 長い名前が指定されていなければ、prefixがあれば "prefix/name" がパスになる
+        // (GNU形式ではprefixの位置に別の情報が入っているので使わない)
+        let mut path_buf = [0; PATH_MAX];
+        let len = if let Some(buf) = ext.path {
+            path_buf = buf;
+            field_str(&buf).len()
+        } else {
+            let prefix = if gnu { &[] } else { field_str(&header.prefix) };
+            let name = field_str(&header.name);
+            let mut len = 0;
+            if !prefix.is_empty() {
+                path_buf[0..prefix.len()].copy_from_slice(prefix);
+                path_buf[prefix.len()] = b'/';
+                len = prefix.len() + 1;
+            }
+            path_buf[len..(len + name.len())].copy_from_slice(name);
+            len + name.len()
+        };
+        let path = normalize(core::str::from_utf8(&path_buf[0..len]).unwrap_or(""));
+        let header_off = entry_off.take().unwrap_or(off);
+        pending = Extension::new();
+
+        // デバイスファイルなどは索引に載せない (ディスク上にはそのまま残す)
+        let kind = match header.type_ {
+            b'0' | b'\0' | b'7' => Some(FileKind::Regular),
+            b'1' => Some(FileKind::HardLink),
+            b'2' => Some(FileKind::Symlink),
+            b'5' => Some(FileKind::Directory),
+            _ => None,
+        };
+        if path.is_empty() {
+            // "./" のようなルートディレクトリ自体のエントリ
+        } else if let Some(kind) = kind {
+            // 中のエントリから補ったディレクトリが先にあれば、そこにヘッダの情報を入れる
+            let file = match find(path) {
+                Ok(file) if file.implicit && kind == FileKind::Directory => file,
+                _ => alloc_file(),
+            };
+            *file = File::new();
+            file.in_use = true;
+            file.name[0..path.len()].copy_from_slice(path.as_bytes());
+            file.kind = kind;
+            file.size = filesz;
+            file.mtime = ext.mtime.unwrap_or(num_field(&header.mtime));
+            file.mode = num_field(&header.mode) as u32 & 0o7777;
+            file.uid = ext.uid.unwrap_or(num_field(&header.uid) as u32);
+            file.gid = ext.gid.unwrap_or(num_field(&header.gid) as u32);
+            file.uname = ext
+                .uname
+                .unwrap_or(Extension::owner_name(field_str(&header.uname)));
+            file.gname = ext
+                .gname
+                .unwrap_or(Extension::owner_name(field_str(&header.gname)));
+            let link = match ext.link {
+                Some(ref link) => field_str(link),
+                None => field_str(&header.linkname),
+            };
+            // ハードリンクはアーカイブ中のパスを指すのでほかのエントリと同じ形にする
+            let link = match kind {
+                FileKind::HardLink => {
+                    normalize(core::str::from_utf8(link).unwrap_or("")).as_bytes()
+                }
+                _ => link,
+            };
+            file.link[0..link.len()].copy_from_slice(link);
+            file.header_off = header_off;
+            file.ext_len = off - header_off;
+            info!("file: {}, size={}", file.path(), file.size);
+            add_parents(path, file.mtime);
+        } else {
+            warn!("tarfs: skipping {path} (type {})", header.type_ as char);
+        }
+
+        off += HEADER_SIZE + padded_size(filesz);
+    }
+
+    if off > capacity {
+        warn!("tarfs: archive is truncated");
+        return Err(());
+    }
+    ARCHIVE_END = off;
+    Ok(())
+}
 
-        let file = &mut FILES[i];
+/// `path` の親ディレクトリのうち、アーカイブにエントリがないものをメモリ上に補う
+///
+/// `tar cf disk.tar bin/sh` のようにディレクトリのエントリを含まないアーカイブでも
+/// 中のファイルにたどり着けるようにする。ヘッダは書き直すときに初めて追加する
+unsafe fn add_parents(path: &str, mtime: u64) {
+    let mut dir = parent_of(path);
+    while !dir.is_empty() && find(dir).is_err() {
+        let file = alloc_file();
+        *file = File::new();
         file.in_use = true;
-        file.name[0..name.len()].copy_from_slice(name.as_bytes());
-        if filesz > 0 {
-            file.data[0..filesz].copy_from_slice(slice::from_raw_parts(
-                &header.data as *const [u8] as *const u8,
-                filesz,
-            ));
-        }
-        file.size = filesz;
-        println!(
-            "file: {}, size={}",
-            &core::str::from_utf8(&file.name).unwrap()[0..(ascii_len(&file.name as *const u8) - 1)],
-            file.size,
-        );
+        file.name[0..dir.len()].copy_from_slice(dir.as_bytes());
+        file.kind = FileKind::Directory;
+        file.mode = 0o755;
+        file.mtime = mtime;
+        file.implicit = true;
+        dir = parent_of(dir);
+    }
+}
 
-        off += align_up(
-            (mem::size_of::<TarHeader>() + filesz) as u64,
-            SECTOR_SIZE as u64,
-        ) as usize;
+/// `off` から終端を示す空のブロックが続いているか
+unsafe fn is_end_blocks(off: u64) -> Result<bool, ()> {
+    let buf = &mut *ptr::addr_of_mut!(MOVE_BUF);
+    for i in 0..END_BLOCKS {
+        bcache::read(disk(), off + i * SECTOR_SIZE as u64, buf)?;
+        if buf.iter().any(|b| *b != 0) {
+            return Ok(false);
+        }
     }
+    Ok(true)
 }
 
-pub unsafe fn flush() {
-    let mut off = 0;
-    for file_i in 0..FILES_MAX {
-        let file = &mut FILES[file_i];
-        if !file.in_use {
-            continue;
-        }
-
-        let header = (&mut DISK[off] as *mut u8 as *mut TarHeader)
-            .as_mut()
-            .unwrap();
-        let name = &file.name;
-        header.name[0..file.name.len()].copy_from_slice(name);
-        let mode = b"0000644\0";
-        header.mode[0..mode.len()].copy_from_slice(mode);
-        let magic = b"ustar\0";
-        header.magic[0..magic.len()].copy_from_slice(magic);
-        let version = b"00";
-        header.version[0..version.len()].copy_from_slice(version);
-        header.type_ = b'0';
-
-        // ファイルサイズを8進数文字列に変換
-        let mut filesz = file.size;
-        for i in 0..(header.size.len() - 1) {
-            header.size[(header.size.len() - 2) - i] = (filesz % 8) as u8 + b'0';
-            filesz /= 8;
-        }
-        header.size[header.size.len() - 1] = b'\0';
-
-        // チェックサムを計算
-        let mut checksum = b' ' as usize * mem::size_of_val(&header.checksum);
-        checksum += header.name.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.mode.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.uid.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.gid.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.size.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.mtime.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.type_ as usize;
-        checksum += header.linkname.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.magic.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.version.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.uname.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.gname.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.devmajor.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.devminor.iter().fold(0, |sum, i| sum + *i as usize);
-        checksum += header.prefix.iter().fold(0, |sum, i| sum + *i as usize);
-
-        for i in 0..6 {
-            header.checksum[(header.checksum.len() - 3) - i] = (checksum % 8) as u8 + b'0';
-            checksum /= 8;
-        }
-
-        // ファイルデータをコピー
-        let header_data =
-            slice::from_raw_parts_mut(&mut header.data as *mut [u8] as *mut u8, file.size);
-        if file.size > 0 {
-            header_data.copy_from_slice(&file.data[0..file.size]);
-        }
-
-        off += align_up(
-            (mem::size_of::<TarHeader>() + file.size) as u64,
-            SECTOR_SIZE as u64,
-        ) as usize;
-    }
-
-    // DISK変数の内容をディスクに書き込む
-    for sector in 0..(mem::size_of_val(&DISK) / SECTOR_SIZE as usize) {
-        if let Err(_) = read_write_disk(
-            &mut DISK[sector * SECTOR_SIZE as usize] as &mut u8,
-            sector as u32,
-            true,
-        ) {
+/// ustarのヘッダの数値フィールド (uid, gid) に書ける最大値
+const USTAR_ID_MAX: u32 = 0o7777777;
+/// ustarのヘッダのサイズのフィールドに書ける最大値
+const USTAR_SIZE_MAX: u64 = 0o77777777777;
+
+/// paxの拡張ヘッダの内容を組み立てるバッファ
+struct PaxWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl PaxWriter<'_> {
+    fn push(&mut self, bytes: &[u8]) -> Result<(), ()> {
+        let end = self.len + bytes.len();
+        if end > self.buf.len() {
+            return Err(());
+        }
+        self.buf[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    /// "<長さ> <キー>=<値>\n" というレコードを追加する (長さはレコード全体のバイト数)
+    fn record(&mut self, key: &str, value: &[u8]) -> Result<(), ()> {
+        let body = 1 + key.len() + 1 + value.len() + 1;
+        // 長さの桁数も長さに含まれるので、桁数が合うものを探す
+        let mut digits = [0; 20];
+        let len = (1..digits.len())
+            .map(|n| body + n)
+            .find(|len| decimal(*len as u64, &mut digits).len() == len - body)
+            .ok_or(())?;
+        self.push(decimal(len as u64, &mut digits))?;
+        self.push(b" ")?;
+        self.push(key.as_bytes())?;
+        self.push(b"=")?;
+        self.push(value)?;
+        self.push(b"\n")
+    }
+}
+
+/// `n` を10進数の文字列にする
+fn decimal(mut n: u64, buf: &mut [u8; 20]) -> &[u8] {
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = (n % 10) as u8 + b'0';
+        n /= 10;
+        if n == 0 {
             break;
         }
     }
+    &buf[i..]
+}
+
+/// ustarのヘッダに収まらない値をpaxの拡張ヘッダの内容として `EXT_BUF` に書き、そのバイト数を返す
+unsafe fn pax_data(file: &File) -> Result<usize, ()> {
+    let mut pax = PaxWriter {
+        buf: &mut *ptr::addr_of_mut!(EXT_BUF),
+        len: 0,
+    };
+    let mut digits = [0; 20];
+    if split_path(file.path(), file.kind).is_none() {
+        pax.record("path", file.path().as_bytes())?;
+    }
+    if file.link().len() > 100 {
+        pax.record("linkpath", file.link().as_bytes())?;
+    }
+    if file.uid > USTAR_ID_MAX {
+        pax.record("uid", decimal(file.uid as u64, &mut digits))?;
+    }
+    if file.gid > USTAR_ID_MAX {
+        pax.record("gid", decimal(file.gid as u64, &mut digits))?;
+    }
+    if file.size as u64 > USTAR_SIZE_MAX {
+        pax.record("size", decimal(file.size as u64, &mut digits))?;
+    }
+    Ok(pax.len)
+}
+
+/// 内容が `pax_len` バイトのpaxの拡張ヘッダ全体のサイズ (内容がなければ拡張ヘッダは付けない)
+fn pax_ext_len(pax_len: usize) -> u64 {
+    if pax_len == 0 {
+        0
+    } else {
+        HEADER_SIZE + padded_size(pax_len)
+    }
+}
+
+/// ヘッダを書き直したときの、ustarのヘッダの前に付く拡張ヘッダのサイズ
+unsafe fn ext_len(file: &File) -> Result<u64, ()> {
+    Ok(pax_ext_len(pax_data(file)?))
+}
 
-    println!("wrote {} bytes to disk", mem::size_of_val(&DISK));
+/// アーカイブが `grow` バイト伸びても終端のブロックまでディスクに収まるか
+unsafe fn has_room(grow: u64) -> bool {
+    let capacity = (*disk()).capacity_sectors() * SECTOR_SIZE as u64;
+    if ARCHIVE_END + grow + END_BLOCKS * SECTOR_SIZE as u64 > capacity {
+        warn!("tarfs: disk is full");
+        return false;
+    }
+    true
+}
+
+/// マジックとバージョンを入れてチェックサムを計算する
+fn finish_header(header: &mut TarHeader) {
+    let magic = b"ustar\0";
+    header.magic[0..magic.len()].copy_from_slice(magic);
+    let version = b"00";
+    header.version[0..version.len()].copy_from_slice(version);
+
+    let mut checksum = checksum(header);
+    for i in 0..6 {
+        header.checksum[(header.checksum.len() - 3) - i] = (checksum % 8) as u8 + b'0';
+        checksum /= 8;
+    }
 }
 
-pub fn lookup(filename: &str) -> Result<*mut File, ()> {
-    for i in 0..FILES_MAX {
-        let file = unsafe { &FILES[i] };
-        let name =
-            &core::str::from_utf8(&file.name).unwrap()[0..(ascii_len(&file.name as *const u8) - 1)];
-        if name == filename {
-            return Ok(file as *const File as *mut File);
+/// `EXT_BUF` の先頭 `len` バイトを、ファイルのpaxの拡張ヘッダとしてキャッシュに書き込む
+unsafe fn write_pax_header(file: &File, len: usize) -> Result<(), ()> {
+    let mut header: TarHeader = mem::zeroed();
+    let header = &mut header;
+    // 拡張ヘッダの名前は読むときには使わないので、GNU tarと同じような名前にしておく
+    let prefix = b"PaxHeaders/";
+    let basename = file.basename().as_bytes();
+    let name_len = basename.len().min(header.name.len() - prefix.len());
+    header.name[0..prefix.len()].copy_from_slice(prefix);
+    header.name[prefix.len()..(prefix.len() + name_len)].copy_from_slice(&basename[0..name_len]);
+    int2oct(0o644, &mut header.mode);
+    int2oct(0, &mut header.uid);
+    int2oct(0, &mut header.gid);
+    int2oct(len as u64, &mut header.size);
+    int2oct(file.mtime, &mut header.mtime);
+    header.type_ = b'x';
+    finish_header(header);
+
+    let data = &*ptr::addr_of!(EXT_BUF);
+    let data = &data[0..len];
+    let padding = (padded_size(len) - len as u64) as usize;
+    bcache::write(disk(), file.header_off, header_bytes(header))?;
+    bcache::write(disk(), file.header_off + HEADER_SIZE, data)?;
+    bcache::write(
+        disk(),
+        file.header_off + HEADER_SIZE + len as u64,
+        &ZERO_SECTOR[0..padding],
+    )
+}
+
+/// ファイルのヘッダをキャッシュに書き込む
+///
+/// ustarのフィールドに収まらない値があればpaxの拡張ヘッダを前に付ける。
+/// 読み込んだときのGNUやpaxの拡張ヘッダは書き直したものに置き換わる
+unsafe fn write_header(file: &mut File) -> Result<(), ()> {
+    // ヘッダのないディレクトリはアーカイブの終わりにヘッダを追加する
+    if file.implicit {
+        if !has_room(HEADER_SIZE) {
+            return Err(());
         }
+        file.header_off = ARCHIVE_END;
+        file.ext_len = 0;
+        file.implicit = false;
+        ARCHIVE_END += HEADER_SIZE;
+        mark_relayout(file.header_off);
+        write_end_blocks()?;
+    }
+
+    let pax_len = pax_data(file)?;
+    let ext_len = pax_ext_len(pax_len);
+    if ext_len != file.ext_len {
+        // ustarのヘッダから後ろをずらす。このファイルの先頭は動かさない
+        let header_off = file.header_off;
+        move_tail(header_off + file.ext_len, header_off + ext_len)?;
+        file.header_off = header_off;
+        file.ext_len = ext_len;
+    }
+    file.dirty = true;
+    if pax_len > 0 {
+        write_pax_header(file, pax_len)?;
+    }
+
+    let mut header: TarHeader = mem::zeroed();
+    let header = &mut header;
+    // 収まらないパスは拡張ヘッダにあるので、ここには切り詰めた名前を入れておく
+    let (prefix, name) = match split_path(file.path(), file.kind) {
+        Some((prefix, name)) => (prefix.as_bytes(), name.as_bytes()),
+        None => {
+            let basename = file.basename().as_bytes();
+            (
+                &[][..],
+                &basename[0..basename.len().min(header.name.len() - 1)],
+            )
+        }
+    };
+    header.prefix[0..prefix.len()].copy_from_slice(prefix);
+    header.name[0..name.len()].copy_from_slice(name);
+    let type_ = match file.kind {
+        FileKind::Regular => b'0',
+        FileKind::HardLink => b'1',
+        FileKind::Symlink => b'2',
+        FileKind::Directory => b'5',
+    };
+    int2oct(file.mode as u64, &mut header.mode);
+    int2oct(file.uid.min(USTAR_ID_MAX) as u64, &mut header.uid);
+    int2oct(file.gid.min(USTAR_ID_MAX) as u64, &mut header.gid);
+    header.uname = file.uname;
+    header.gname = file.gname;
+    let link = file.link().as_bytes();
+    let link = &link[0..link.len().min(header.linkname.len())];
+    header.linkname[0..link.len()].copy_from_slice(link);
+    header.type_ = type_;
+    // ディレクトリは名前の末尾に'/'を付ける
+    if file.kind == FileKind::Directory {
+        header.name[name.len()] = b'/';
+    }
+
+    // ファイルサイズと更新時刻を8進数文字列に変換
+    int2oct((file.size as u64).min(USTAR_SIZE_MAX), &mut header.size);
+    int2oct(file.mtime, &mut header.mtime);
+    finish_header(header);
+
+    bcache::write(disk(), file.data_off() - HEADER_SIZE, header_bytes(header))
+}
+
+/// `from` からアーカイブの終わりまでを `to` に移動し、後ろのファイルのオフセットを更新する
+unsafe fn move_tail(from: u64, to: u64) -> Result<(), ()> {
+    if from == to {
+        return Ok(());
+    }
+
+    let capacity = (*disk()).capacity_sectors() * SECTOR_SIZE as u64;
+    let new_end = ARCHIVE_END - from + to;
+    if new_end + END_BLOCKS * SECTOR_SIZE as u64 > capacity {
+        warn!("tarfs: disk is full");
+        return Err(());
+    }
+
+    // 重なっている場合に上書きしないよう、後ろに動かすときは末尾からコピーする
+    let buf = &mut *ptr::addr_of_mut!(MOVE_BUF);
+    let sectors = (ARCHIVE_END - from) / SECTOR_SIZE as u64;
+    for i in 0..sectors {
+        let i = if to > from { sectors - 1 - i } else { i };
+        let off = i * SECTOR_SIZE as u64;
+        bcache::read(disk(), from + off, buf)?;
+        bcache::write(disk(), to + off, buf)?;
+    }
+
+    for file in files() {
+        if file.header_off >= from {
+            file.header_off = file.header_off - from + to;
+        }
+    }
+    ARCHIVE_END = new_end;
+    mark_relayout(from.min(to));
+
+    // 縮んだ場合は古い終端の後ろに残ったデータが見えないように終端を書き直す
+    write_end_blocks()
+}
+
+unsafe fn mark_relayout(off: u64) {
+    RELAYOUT_FROM = Some(RELAYOUT_FROM.map_or(off, |from| from.min(off)));
+}
+
+unsafe fn write_end_blocks() -> Result<(), ()> {
+    for i in 0..END_BLOCKS {
+        bcache::write(disk(), ARCHIVE_END + i * SECTOR_SIZE as u64, &ZERO_SECTOR)?;
+    }
+    Ok(())
+}
+
+/// ファイルの `offset` バイト目から読み込み、読んだバイト数を返す
+unsafe fn read(file: &File, offset: usize, buf: &mut [u8]) -> Result<usize, ()> {
+    if offset >= file.size {
+        return Ok(0);
+    }
+    let len = buf.len().min(file.size - offset);
+    bcache::read(disk(), file.data_off() + offset as u64, &mut buf[0..len])?;
+    Ok(len)
+}
+
+/// ファイルのサイズを `size` に変える。増えた部分は0で埋める
+unsafe fn resize(file: &mut File, size: usize) -> Result<(), ()> {
+    // サイズが変わってセクタ数が変わる場合は後ろのエントリをずらす
+    let old_end = file.end_off();
+    let new_end = file.data_off() + padded_size(size);
+    // 後ろのエントリを動かしてからヘッダを書く場所がないと分かっても戻せないので、
+    // 拡張ヘッダが増える分も含めて先に空きを確かめる
+    let mut resized = *file;
+    resized.size = size;
+    let grow = (new_end + ext_len(&resized)?).saturating_sub(old_end + file.ext_len);
+    if !has_room(grow) {
+        return Err(());
+    }
+    move_tail(old_end, new_end)?;
+
+    if size > file.size {
+        // 最後のセクタの余りはもともと0なので、増えたセクタだけを0で埋める
+        let mut off = old_end;
+        while off < new_end {
+            bcache::write(disk(), off, &ZERO_SECTOR)?;
+            off += SECTOR_SIZE as u64;
+        }
+    } else {
+        let padding = (padded_size(size) - size as u64) as usize;
+        bcache::write(
+            disk(),
+            file.data_off() + size as u64,
+            &ZERO_SECTOR[0..padding],
+        )?;
+    }
+
+    file.size = size;
+    file.mtime = rtc::now_secs();
+    write_header(file)
+}
+
+/// ファイルの `offset` バイト目から `data` を書き込む
+unsafe fn write(file: &mut File, offset: usize, data: &[u8]) -> Result<(), ()> {
+    if offset + data.len() > file.size {
+        resize(file, offset + data.len())?;
+    }
+    bcache::write(disk(), file.data_off() + offset as u64, data)?;
+    file.mtime = rtc::now_secs();
+    write_header(file)
+}
+
+/// `start` から `end` バイト目までを含むセクタのうち、変更されたものを書き戻す
+unsafe fn write_back(start: u64, end: u64) -> Result<(), ()> {
+    let sector = SECTOR_SIZE as u64;
+    bcache::write_back(disk(), (start / sector)..align_up(end, sector) / sector)
+}
+
+/// レイアウトが変わっていれば、変わった範囲から終端のブロックまでを書き戻す
+unsafe fn write_back_relayout() -> Result<(), ()> {
+    if let Some(from) = RELAYOUT_FROM {
+        write_back(from, ARCHIVE_END + END_BLOCKS * SECTOR_SIZE as u64)?;
+        RELAYOUT_FROM = None;
+        for file in files().filter(|file| file.header_off >= from) {
+            file.dirty = false;
+        }
+    }
+    Ok(())
+}
+
+/// ファイルの変更をディスクに書き戻す
+///
+/// ほかのエントリの移動が終わっていないとアーカイブとして読めないので、
+/// レイアウトが変わっていればその範囲も書き戻す
+unsafe fn fsync(file: &mut File) -> Result<(), ()> {
+    write_back_relayout()?;
+    if file.dirty {
+        write_back(file.header_off, file.end_off())?;
+        file.dirty = false;
+    }
+    (*disk()).flush()
+}
+
+/// 変更されたファイルとレイアウトが変わった範囲だけをディスクに書き戻す
+unsafe fn flush() -> Result<(), ()> {
+    write_back_relayout()?;
+    for file in files().filter(|file| file.dirty) {
+        write_back(file.header_off, file.end_off())?;
+        file.dirty = false;
+    }
+    (*disk()).flush()
+}
+
+/// アーカイブ中のパスが `path` であるエントリを探す (リンクはたどらない)
+unsafe fn find(path: &str) -> Result<&'static mut File, ()> {
+    files().find(|file| file.path() == path).ok_or(())
+}
+
+fn bytes_str(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("")
+}
+
+/// ルートディレクトリにはエントリがないので特別な番号を使う
+const ROOT_INO: Ino = 0;
+
+/// ファイルエントリのアドレスを `Ino` として使う (エントリのメモリは解放しない)
+fn ino(file: &File) -> Ino {
+    file as *const File as Ino
+}
+
+unsafe fn file(ino: Ino) -> Result<&'static mut File, ()> {
+    if ino == ROOT_INO {
+        return Err(());
+    }
+    let file = &mut *(ino as *mut File);
+    if !file.in_use {
+        return Err(());
+    }
+    Ok(file)
+}
+
+/// ディレクトリ `dir` のパス (ルートなら空文字列)
+unsafe fn dir_path(dir: Ino) -> Result<&'static str, ()> {
+    if dir == ROOT_INO {
+        return Ok("");
+    }
+    let dir = file(dir)?;
+    if dir.kind != FileKind::Directory {
+        return Err(());
+    }
+    Ok(dir.path())
+}
+
+/// ディレクトリ `dir` の中の `name` のアーカイブ中でのパスを `buf` に作る
+unsafe fn join<'a>(dir: Ino, name: &[u8], buf: &'a mut [u8; PATH_MAX]) -> Result<&'a str, ()> {
+    let dir = dir_path(dir)?;
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+        return Err(());
+    }
+    let sep = if dir.is_empty() { 0 } else { 1 };
+    let len = dir.len() + sep + name.len();
+    if len >= PATH_MAX {
+        return Err(());
+    }
+    buf[0..dir.len()].copy_from_slice(dir.as_bytes());
+    if sep == 1 {
+        buf[dir.len()] = b'/';
+    }
+    buf[(dir.len() + sep)..len].copy_from_slice(name);
+    core::str::from_utf8(&buf[0..len]).map_err(|_| ())
+}
+
+/// ディレクトリ `dir` の直下にあるエントリをたどる
+unsafe fn children(dir: &str) -> impl Iterator<Item = &'static mut File> + '_ {
+    files().filter(move |file| parent_of(file.path()) == dir)
+}
+
+/// アーカイブの終わりに新しいエントリを追加する
+unsafe fn append(path: &str, kind: FileKind) -> Result<&'static mut File, ()> {
+    if find(path).is_ok() {
+        return Err(());
+    }
+
+    let file = alloc_file();
+    *file = File::new();
+    file.in_use = true;
+    file.name[0..path.len()].copy_from_slice(path.as_bytes());
+    file.kind = kind;
+    file.mode = match kind {
+        FileKind::Directory => 0o755,
+        _ => 0o644,
+    };
+    file.mtime = rtc::now_secs();
+    file.header_off = ARCHIVE_END;
+    let fits = ext_len(file).is_ok_and(|ext_len| has_room(ext_len + HEADER_SIZE));
+    if !fits || write_header(file).is_err() {
+        file.in_use = false;
+        return Err(());
+    }
+
+    ARCHIVE_END += HEADER_SIZE;
+    mark_relayout(file.header_off);
+    write_end_blocks()?;
+    Ok(file)
+}
+
+/// `target` を指すハードリンクをたどる
+unsafe fn hard_links(target: &[u8; PATH_MAX]) -> impl Iterator<Item = &'static mut File> + '_ {
+    files().filter(move |file| file.kind == FileKind::HardLink && file.link == *target)
+}
+
+/// エントリをアーカイブから取り除き、後ろのエントリを詰める
+unsafe fn remove(file: &mut File) -> Result<(), ()> {
+    // ヘッダのないディレクトリはメモリ上にしかない
+    if file.implicit {
+        file.in_use = false;
+        return Ok(());
+    }
+
+    // ハードリンクが残っていれば、最初のリンクの名前に変えて内容を残す
+    if file.kind == FileKind::Regular {
+        let old = file.name;
+        let link = hard_links(&old).next();
+        if let Some(link) = link {
+            let new = link.name;
+            remove(link)?;
+            file.name = new;
+            write_header(file)?;
+            for other in hard_links(&old) {
+                other.link = new;
+                write_header(other)?;
+            }
+            return Ok(());
+        }
+    }
+
+    move_tail(file.end_off(), file.header_off)?;
+    file.in_use = false;
+    Ok(())
+}
+
+fn set_path(buf: &mut [u8; PATH_MAX], path: &str) {
+    *buf = [0; PATH_MAX];
+    buf[0..path.len()].copy_from_slice(path.as_bytes());
+}
+
+/// `old` を `new` に名前を変える。ディレクトリなら中身もまとめて移動する
+///
+/// `new` が同じ種類のエントリ (ディレクトリなら空のもの) として存在すれば置き換える
+unsafe fn rename(old: &str, new: &str) -> Result<(), ()> {
+    let file = find(old)?;
+    if new == old {
+        return Err(());
+    }
+    // ディレクトリを自分の中には移動できない
+    let is_descendant = |path: &str| {
+        path.len() > old.len() && path.starts_with(old) && path.as_bytes()[old.len()] == b'/'
+    };
+    if is_descendant(new) {
+        return Err(());
+    }
+
+    // ヘッダを書き換える前に、移動後のパスの長さと拡張ヘッダが増える分の空きを確かめる
+    let mut buf = [0; PATH_MAX];
+    let renamed = |path: &str, buf: &mut [u8; PATH_MAX]| -> Option<usize> {
+        let rest = &path[old.len()..];
+        let len = new.len() + rest.len();
+        if len >= PATH_MAX {
+            return None;
+        }
+        buf[0..new.len()].copy_from_slice(new.as_bytes());
+        buf[new.len()..len].copy_from_slice(rest.as_bytes());
+        Some(len)
+    };
+    let moves_link = |link: &File| {
+        link.kind == FileKind::HardLink && (link.link() == old || is_descendant(link.link()))
+    };
+    let grow = |moved: &File, file: &File| -> Result<u64, ()> {
+        let header = if file.implicit { HEADER_SIZE } else { 0 };
+        Ok((ext_len(moved)? + header).saturating_sub(file.ext_len))
+    };
+    let mut moved = *file;
+    set_path(&mut moved.name, new);
+    let mut total = grow(&moved, file)?;
+    for child in files().filter(|f| is_descendant(f.path())) {
+        let len = renamed(child.path(), &mut buf).ok_or(())?;
+        let mut moved = *child;
+        set_path(&mut moved.name, bytes_str(&buf[0..len]));
+        total += grow(&moved, child)?;
+    }
+    for link in files().filter(|f| moves_link(f)) {
+        let len = renamed(link.link(), &mut buf).ok_or(())?;
+        let mut moved = *link;
+        set_path(&mut moved.link, bytes_str(&buf[0..len]));
+        total += grow(&moved, link)?;
+    }
+    if !has_room(total) {
+        return Err(());
+    }
+
+    if let Ok(target) = find(new) {
+        if target.kind != file.kind
+            || (target.kind == FileKind::Directory && children(target.path()).next().is_some())
+        {
+            return Err(());
+        }
+        remove(target)?;
+    }
+
+    for link in files().filter(|f| moves_link(f)) {
+        let len = renamed(link.link(), &mut buf).unwrap();
+        set_path(&mut link.link, bytes_str(&buf[0..len]));
+        write_header(link)?;
+    }
+    for child in files().filter(|f| is_descendant(f.path())) {
+        let len = renamed(child.path(), &mut buf).unwrap();
+        set_path(&mut child.name, bytes_str(&buf[0..len]));
+        write_header(child)?;
+    }
+    set_path(&mut file.name, new);
+    write_header(file)
+}
+
+/// tarアーカイブを読み込んだファイルシステム
+///
+/// アーカイブの情報は静的変数に置いているので、同時に1つしかマウントできない
+struct TarFs;
+
+static mut TARFS: TarFs = TarFs;
+
+impl FileSystem for TarFs {
+    fn root(&self) -> Ino {
+        ROOT_INO
+    }
+
+    fn lookup(&mut self, dir: Ino, name: &[u8]) -> Result<Ino, ()> {
+        let mut buf = [0; PATH_MAX];
+        unsafe {
+            let path = match name {
+                b"." => {
+                    dir_path(dir)?;
+                    return Ok(dir);
+                }
+                b".." => match parent_of(dir_path(dir)?) {
+                    "" => return Ok(ROOT_INO),
+                    parent => parent,
+                },
+                _ => join(dir, name, &mut buf)?,
+            };
+            let file = find(path)?;
+            // ハードリンクは指す先と同じファイルとして扱う
+            if file.kind == FileKind::HardLink {
+                return Ok(ino(find(file.link())?));
+            }
+            Ok(ino(file))
+        }
+    }
+
+    fn stat(&mut self, ino: Ino) -> Result<Stat, ()> {
+        if ino == ROOT_INO {
+            return Ok(Stat {
+                mode: S_IFDIR | 0o755,
+                uid: 0,
+                gid: 0,
+                nlink: 1,
+                size: 0,
+                mtime: 0,
+            });
+        }
+
+        let file = unsafe { file(ino)? };
+        let (type_, size) = match file.kind {
+            FileKind::Directory => (S_IFDIR, 0),
+            FileKind::Symlink => (S_IFLNK, file.link().len()),
+            _ => (S_IFREG, file.size),
+        };
+        Ok(Stat {
+            mode: type_ | file.mode,
+            uid: file.uid,
+            gid: file.gid,
+            nlink: 1 + unsafe { hard_links(&file.name) }.count() as u32,
+            size: size as u64,
+            mtime: file.mtime,
+        })
+    }
+
+    fn read(&mut self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, ()> {
+        let file = unsafe { file(ino)? };
+        if file.kind != FileKind::Regular {
+            return Err(());
+        }
+        unsafe { read(file, offset, buf) }
+    }
+
+    fn write(&mut self, ino: Ino, offset: usize, data: &[u8]) -> Result<usize, ()> {
+        let file = unsafe { file(ino)? };
+        if file.kind != FileKind::Regular {
+            return Err(());
+        }
+        unsafe { write(file, offset, data)? };
+        Ok(data.len())
+    }
+
+    fn truncate(&mut self, ino: Ino, size: usize) -> Result<(), ()> {
+        let file = unsafe { file(ino)? };
+        if file.kind != FileKind::Regular {
+            return Err(());
+        }
+        unsafe { resize(file, size) }
+    }
+
+    fn readlink(&mut self, ino: Ino, buf: &mut [u8]) -> Result<usize, ()> {
+        let file = unsafe { file(ino)? };
+        let link = file.link().as_bytes();
+        if file.kind != FileKind::Symlink || link.len() > buf.len() {
+            return Err(());
+        }
+        buf[0..link.len()].copy_from_slice(link);
+        Ok(link.len())
+    }
+
+    fn readdir(&mut self, dir: Ino, index: usize) -> Result<Option<DirEntry>, ()> {
+        unsafe {
+            let Some(file) = children(dir_path(dir)?).nth(index) else {
+                return Ok(None);
+            };
+            let kind = match file.kind {
+                FileKind::Regular | FileKind::HardLink => FileType::Regular,
+                FileKind::Directory => FileType::Directory,
+                FileKind::Symlink => FileType::Symlink,
+            };
+            DirEntry::new(file.basename().as_bytes(), kind).map(Some)
+        }
+    }
+
+    fn create(&mut self, dir: Ino, name: &[u8], kind: FileType) -> Result<Ino, ()> {
+        let kind = match kind {
+            FileType::Regular => FileKind::Regular,
+            FileType::Directory => FileKind::Directory,
+            FileType::Symlink => return Err(()),
+        };
+        let mut buf = [0; PATH_MAX];
+        unsafe {
+            let path = join(dir, name, &mut buf)?;
+            append(path, kind).map(|file| ino(file))
+        }
+    }
+
+    fn unlink(&mut self, dir: Ino, name: &[u8]) -> Result<(), ()> {
+        let mut buf = [0; PATH_MAX];
+        unsafe {
+            let file = find(join(dir, name, &mut buf)?)?;
+            if file.kind == FileKind::Directory {
+                return Err(());
+            }
+            remove(file)
+        }
+    }
+
+    fn rmdir(&mut self, dir: Ino, name: &[u8]) -> Result<(), ()> {
+        let mut buf = [0; PATH_MAX];
+        unsafe {
+            let file = find(join(dir, name, &mut buf)?)?;
+            if file.kind != FileKind::Directory || children(file.path()).next().is_some() {
+                return Err(());
+            }
+            remove(file)
+        }
+    }
+
+    fn rename(
+        &mut self,
+        old_dir: Ino,
+        old_name: &[u8],
+        new_dir: Ino,
+        new_name: &[u8],
+    ) -> Result<(), ()> {
+        let mut old_buf = [0; PATH_MAX];
+        let mut new_buf = [0; PATH_MAX];
+        unsafe {
+            let old = join(old_dir, old_name, &mut old_buf)?;
+            let new = join(new_dir, new_name, &mut new_buf)?;
+            rename(old, new)
+        }
+    }
+
+    fn fsync(&mut self, ino: Ino) -> Result<(), ()> {
+        unsafe {
+            if ino == ROOT_INO {
+                write_back_relayout()?;
+                return (*disk()).flush();
+            }
+            fsync(file(ino)?)
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), ()> {
+        unsafe { flush() }
     }
-    Err(())
 }
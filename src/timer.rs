@@ -0,0 +1,209 @@
+use crate::{
+    fdt::PLATFORM,
+    process::{self, CURRENT_PROC},
+    read_csr, sbi, write_csr,
+};
+
+/// QEMU virtのtimebase-frequency (10MHz)
+const DEFAULT_TIMEBASE_FREQ: u64 = 10_000_000;
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+const TIMERS_MAX: usize = 32;
+const SIE_STIE: u64 = 1 << 5;
+
+/// ユーザープログラムとやり取りする `struct timespec`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    pub fn from_ns(ns: u64) -> Self {
+        Self {
+            tv_sec: (ns / NSEC_PER_SEC) as i64,
+            tv_nsec: (ns % NSEC_PER_SEC) as i64,
+        }
+    }
+
+    pub fn to_ns(self) -> Result<u64, ()> {
+        if self.tv_sec < 0 || self.tv_nsec < 0 || self.tv_nsec >= NSEC_PER_SEC as i64 {
+            return Err(());
+        }
+        Ok((self.tv_sec as u64)
+            .saturating_mul(NSEC_PER_SEC)
+            .saturating_add(self.tv_nsec as u64))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Timer {
+    id: u64,
+    deadline: u64,
+    callback: fn(u64),
+    arg: u64,
+}
+
+impl Timer {
+    const fn new() -> Self {
+        Self {
+            id: 0,
+            deadline: 0,
+            callback: nop,
+            arg: 0,
+        }
+    }
+}
+
+fn nop(_: u64) {}
+
+/// 期限の早い順に並んだタイマーのリスト
+static mut TIMERS: [Timer; TIMERS_MAX] = [Timer::new(); TIMERS_MAX];
+static mut TIMERS_LEN: usize = 0;
+static mut NEXT_TIMER_ID: u64 = 1;
+static mut TIMEBASE_FREQ: u64 = DEFAULT_TIMEBASE_FREQ;
+
+/// `time` CSRの現在値
+pub fn now() -> u64 {
+    read_csr!("time")
+}
+
+pub fn ns_to_ticks(ns: u64) -> u64 {
+    (ns as u128 * unsafe { TIMEBASE_FREQ } as u128 / NSEC_PER_SEC as u128) as u64
+}
+
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    (ticks as u128 * NSEC_PER_SEC as u128 / unsafe { TIMEBASE_FREQ } as u128) as u64
+}
+
+pub unsafe fn init() {
+    if PLATFORM.timebase_freq != 0 {
+        TIMEBASE_FREQ = PLATFORM.timebase_freq;
+    }
+
+    program_next();
+    write_csr!("sie", read_csr!("sie") | SIE_STIE);
+}
+
+/// `deadline` (`time` CSRの値) を過ぎたら `callback(arg)` を呼び出すタイマーを登録する
+pub unsafe fn add(deadline: u64, callback: fn(u64), arg: u64) -> Result<u64, ()> {
+    if TIMERS_LEN == TIMERS_MAX {
+        return Err(());
+    }
+
+    // 挿入位置を探して後ろのタイマーをずらす
+    let mut pos = TIMERS_LEN;
+    while pos > 0 && TIMERS[pos - 1].deadline > deadline {
+        TIMERS[pos] = TIMERS[pos - 1];
+        pos -= 1;
+    }
+
+    let id = NEXT_TIMER_ID;
+    NEXT_TIMER_ID += 1;
+    TIMERS[pos] = Timer {
+        id,
+        deadline,
+        callback,
+        arg,
+    };
+    TIMERS_LEN += 1;
+
+    if pos == 0 {
+        program_next();
+    }
+
+    Ok(id)
+}
+
+/// 登録済みのタイマーを取り消す。既に発火していた場合は `false` を返す
+pub unsafe fn cancel(id: u64) -> bool {
+    for i in 0..TIMERS_LEN {
+        if TIMERS[i].id == id {
+            for j in i..(TIMERS_LEN - 1) {
+                TIMERS[j] = TIMERS[j + 1];
+            }
+            TIMERS_LEN -= 1;
+            if i == 0 {
+                program_next();
+            }
+            return true;
+        }
+    }
+    false
+}
+
+/// 期限を過ぎたタイマーのコールバックを呼び出す
+pub unsafe fn run_expired() {
+    while TIMERS_LEN > 0 && TIMERS[0].deadline <= now() {
+        let timer = TIMERS[0];
+        for j in 0..(TIMERS_LEN - 1) {
+            TIMERS[j] = TIMERS[j + 1];
+        }
+        TIMERS_LEN -= 1;
+        (timer.callback)(timer.arg);
+    }
+    program_next();
+}
+
+pub unsafe fn handle_interrupt() {
+    run_expired();
+}
+
+unsafe fn program_next() {
+    if TIMERS_LEN > 0 {
+        sbi::set_timer(TIMERS[0].deadline);
+    } else {
+        sbi::set_timer(u64::MAX);
+    }
+}
+
+fn wakeup_sleeper(chan: u64) {
+    unsafe {
+        process::wakeup(chan);
+    }
+}
+
+/// 現在のプロセスを `ns` ナノ秒の間ブロックする
+pub unsafe fn sleep(ns: u64) {
+    let deadline = now() + ns_to_ticks(ns);
+
+    // 起動中などプロセスの外から呼ばれた場合はビジーウェイトする
+    if !process::can_block() {
+        while now() < deadline {
+            run_expired();
+        }
+        return;
+    }
+
+    let chan = CURRENT_PROC as u64;
+    while now() < deadline {
+        if add(deadline, wakeup_sleeper, chan).is_err() {
+            panic!("too many timers");
+        }
+        process::block(chan);
+    }
+}
+
+/// ドライバなどがタイムアウトの判定に使う期限
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(u64);
+
+impl Deadline {
+    pub fn after_ms(ms: u64) -> Self {
+        Self(now() + ns_to_ticks(ms * 1_000_000))
+    }
+
+    pub fn expired(&self) -> bool {
+        now() >= self.0
+    }
+}
+
+/// `chan` で起こされるか `deadline` を過ぎるまで現在のプロセスをブロックする
+pub unsafe fn block_until(chan: u64, deadline: Deadline) {
+    let id = match add(deadline.0, wakeup_sleeper, chan) {
+        Ok(id) => id,
+        Err(_) => panic!("too many timers"),
+    };
+    process::block(chan);
+    cancel(id);
+}
@@ -0,0 +1,76 @@
+use crate::{
+    fdt::PLATFORM, handler::register_irq, info, paging::register_mmio, process, types::PhysAddr,
+};
+
+/// QEMU virtのNS16550A
+const UART_REG_RBR: u64 = 0; // 受信バッファ
+const UART_REG_IER: u64 = 1; // 割り込み許可
+const UART_REG_MCR: u64 = 4; // モデム制御
+const UART_REG_LSR: u64 = 5; // ラインステータス
+const UART_IER_RX_AVAILABLE: u8 = 1 << 0;
+const UART_MCR_OUT2: u8 = 1 << 3;
+const UART_LSR_DATA_READY: u8 = 1 << 0;
+const RX_BUF_SIZE: usize = 64;
+
+static mut UART_PADDR: PhysAddr = PhysAddr::new(0);
+static mut RX_BUF: [u8; RX_BUF_SIZE] = [0; RX_BUF_SIZE];
+static mut RX_HEAD: usize = 0;
+static mut RX_TAIL: usize = 0;
+
+unsafe fn reg_read8(offset: u64) -> u8 {
+    ((UART_PADDR.as_u64() + offset) as *const u8).read_volatile()
+}
+
+unsafe fn reg_write8(offset: u64, value: u8) {
+    ((UART_PADDR.as_u64() + offset) as *mut u8).write_volatile(value);
+}
+
+/// 受信割り込みを設定する。UARTが見つからなければSBIのgetcharを使い続ける
+pub unsafe fn init() {
+    let uart = match PLATFORM.uart {
+        Some(uart) if uart.irq != 0 => uart,
+        _ => return,
+    };
+    if register_irq(uart.irq, handle_irq).is_err() {
+        return;
+    }
+    UART_PADDR = uart.reg.base;
+    register_mmio(UART_PADDR, uart.reg.size);
+
+    reg_write8(UART_REG_MCR, reg_read8(UART_REG_MCR) | UART_MCR_OUT2);
+    reg_write8(UART_REG_IER, UART_IER_RX_AVAILABLE);
+    info!("uart: base={:#x}, irq={}", UART_PADDR.as_u64(), uart.irq);
+}
+
+pub fn is_enabled() -> bool {
+    unsafe { UART_PADDR.as_u64() != 0 }
+}
+
+fn handle_irq(_irq: u32) {
+    unsafe {
+        while reg_read8(UART_REG_LSR) & UART_LSR_DATA_READY != 0 {
+            let ch = reg_read8(UART_REG_RBR);
+            let next = (RX_TAIL + 1) % RX_BUF_SIZE;
+            // バッファが溢れたら捨てる
+            if next != RX_HEAD {
+                RX_BUF[RX_TAIL] = ch;
+                RX_TAIL = next;
+            }
+        }
+        process::wakeup(rx_chan());
+    }
+}
+
+fn rx_chan() -> u64 {
+    unsafe { &RX_BUF as *const [u8] as *const u8 as u64 }
+}
+
+/// 1文字受信するまで現在のプロセスをブロックする
+pub unsafe fn getchar() -> u8 {
+    while RX_HEAD == RX_TAIL {
+        process::block(rx_chan());
+    }
+    let ch = RX_BUF[RX_HEAD];
+    RX_HEAD = (RX_HEAD + 1) % RX_BUF_SIZE;
+    ch
+}
@@ -30,6 +30,12 @@ int strcmp(const char *s1, const char *s2) {
   return *s1 - *s2;
 }
 
+size_t strlen(const char *s) {
+  size_t len = 0;
+  while (s[len]) len++;
+  return len;
+}
+
 void putchar(char ch);
 
 void printf(const char *fmt, ...) {
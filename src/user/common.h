@@ -8,6 +8,21 @@ typedef unsigned long long uint64_t;
 typedef uint64_t size_t;
 typedef uint64_t paddr_t;
 typedef uint64_t vaddr_t;
+typedef long long int64_t;
+
+struct timespec {
+  int64_t tv_sec;
+  int64_t tv_nsec;
+};
+
+struct stat {
+  uint32_t st_mode;
+  uint32_t st_uid;
+  uint32_t st_gid;
+  uint32_t st_nlink;
+  uint64_t st_size;
+  int64_t st_mtime;
+};
 
 #define true 1
 #define false 0
@@ -25,9 +40,32 @@ typedef uint64_t vaddr_t;
 #define SYS_EXIT 3
 #define SYS_READFILE 4
 #define SYS_WRITEFILE 5
+#define SYS_SLEEP 6
+#define SYS_NANOSLEEP 7
+#define SYS_CLOCK_GETTIME 8
+#define SYS_MKDIR 9
+#define SYS_RMDIR 10
+#define SYS_READDIR 11
+#define SYS_OPEN 12
+#define SYS_UNLINK 13
+#define SYS_RENAME 14
+#define SYS_STAT 15
+#define SYS_LSTAT 16
+#define SYS_SYNC 17
+#define SYS_FSYNC 18
+#define SYS_MOUNT 19
+#define O_CREAT 0100
+#define O_TRUNC 01000
+#define S_IFMT 0170000
+#define S_IFREG 0100000
+#define S_IFDIR 0040000
+#define S_IFLNK 0120000
+#define CLOCK_REALTIME 0
+#define CLOCK_MONOTONIC 1
 
 void *memset(void *buf, char c, size_t n);
 void *memcpy(void *dst, const void *src, size_t n);
 char *strcpy(char *dst, const char *src);
 int strcmp(const char *s1, const char *s2);
+size_t strlen(const char *s);
 void printf(const char *fmt, ...);
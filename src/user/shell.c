@@ -20,9 +20,27 @@ void main(void) {
       }
     }
 
+    // 最初の空白でコマンドと引数に分ける
+    char *arg = "";
+    for (char *p = cmdline; *p; p++) {
+      if (*p == ' ') {
+        *p = '\0';
+        arg = p + 1;
+        break;
+      }
+    }
+
     if (strcmp(cmdline, "hello") == 0)
       printf("Hello world from shell!\n");
-    else if (strcmp(cmdline, "exit") == 0)
+    else if (strcmp(cmdline, "sleep") == 0) {
+      struct timespec req = {.tv_sec = 1, .tv_nsec = 0};
+      nanosleep(&req);
+      printf("slept 1 second\n");
+    } else if (strcmp(cmdline, "date") == 0) {
+      struct timespec ts;
+      clock_gettime(CLOCK_REALTIME, &ts);
+      printf("%d\n", (int)ts.tv_sec);
+    } else if (strcmp(cmdline, "exit") == 0)
       exit();
     else if (strcmp(cmdline, "readfile") == 0) {
       char buf[128] = {0};
@@ -31,7 +49,57 @@ void main(void) {
       printf("%s\n", buf);
     } else if (strcmp(cmdline, "writefile") == 0)
       writefile("hello.txt", "Hello from shell!\n", 19);
-    else
+    else if (strcmp(cmdline, "ls") == 0) {
+      char buf[512];
+      int len = readdir(*arg ? arg : "/", buf, sizeof(buf));
+      if (len < 0) {
+        printf("ls: %s: not a directory\n", arg);
+        continue;
+      }
+      for (int i = 0; i < len; i += strlen(&buf[i]) + 1) printf("%s\n", &buf[i]);
+    } else if (strcmp(cmdline, "mkdir") == 0) {
+      if (mkdir(arg) < 0) printf("mkdir: failed to create %s\n", arg);
+    } else if (strcmp(cmdline, "rmdir") == 0) {
+      if (rmdir(arg) < 0) printf("rmdir: failed to remove %s\n", arg);
+    } else if (strcmp(cmdline, "touch") == 0) {
+      if (open(arg, O_CREAT) < 0) printf("touch: failed to create %s\n", arg);
+    } else if (strcmp(cmdline, "rm") == 0) {
+      if (unlink(arg) < 0) printf("rm: failed to remove %s\n", arg);
+    } else if (strcmp(cmdline, "mv") == 0) {
+      char *dst = arg;
+      while (*dst && *dst != ' ') dst++;
+      if (!*dst) {
+        printf("usage: mv <src> <dst>\n");
+        continue;
+      }
+      *dst++ = '\0';
+      if (rename(arg, dst) < 0) printf("mv: failed to rename %s\n", arg);
+    } else if (strcmp(cmdline, "stat") == 0) {
+      struct stat st;
+      if (lstat(arg, &st) < 0) {
+        printf("stat: %s: not found\n", arg);
+        continue;
+      }
+      const char *type = (st.st_mode & S_IFMT) == S_IFDIR   ? "directory"
+                         : (st.st_mode & S_IFMT) == S_IFLNK ? "symbolic link"
+                                                            : "regular file";
+      // printfは8進数を表示できないので1桁ずつ表示する
+      printf("%s: %s, size=%d, mode=%d%d%d, uid=%d, gid=%d, links=%d, mtime=%d\n",
+             arg, type, (int)st.st_size, (st.st_mode >> 6) & 7,
+             (st.st_mode >> 3) & 7, st.st_mode & 7, st.st_uid, st.st_gid,
+             st.st_nlink, (int)st.st_mtime);
+    } else if (strcmp(cmdline, "mount") == 0) {
+      char *path = arg;
+      while (*path && *path != ' ') path++;
+      if (!*path) {
+        printf("usage: mount <device> <dir>\n");
+        continue;
+      }
+      *path++ = '\0';
+      if (mount(arg, path) < 0) printf("mount: failed to mount %s\n", arg);
+    } else if (strcmp(cmdline, "sync") == 0) {
+      if (sync() < 0) printf("sync: failed\n");
+    } else
       printf("unknown command: %s\n", cmdline);
   }
 }
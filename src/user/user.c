@@ -28,6 +28,53 @@ int writefile(const char *filename, const char *buf, uint64_t len) {
   return syscall(SYS_WRITEFILE, (uint64_t)filename, (uint64_t)buf, len);
 }
 
+int sleep(uint64_t seconds) { return syscall(SYS_SLEEP, seconds, 0, 0); }
+
+int nanosleep(const struct timespec *req) {
+  return syscall(SYS_NANOSLEEP, (uint64_t)req, 0, 0);
+}
+
+int clock_gettime(int clk_id, struct timespec *tp) {
+  return syscall(SYS_CLOCK_GETTIME, clk_id, (uint64_t)tp, 0);
+}
+
+int mkdir(const char *path) { return syscall(SYS_MKDIR, (uint64_t)path, 0, 0); }
+
+int rmdir(const char *path) { return syscall(SYS_RMDIR, (uint64_t)path, 0, 0); }
+
+int readdir(const char *path, char *buf, uint64_t len) {
+  return syscall(SYS_READDIR, (uint64_t)path, (uint64_t)buf, len);
+}
+
+// ファイルディスクリプタはまだないので、成功すれば0を返す
+int open(const char *path, int flags) {
+  return syscall(SYS_OPEN, (uint64_t)path, flags, 0);
+}
+
+int creat(const char *path) { return open(path, O_CREAT | O_TRUNC); }
+
+int unlink(const char *path) { return syscall(SYS_UNLINK, (uint64_t)path, 0, 0); }
+
+int rename(const char *oldpath, const char *newpath) {
+  return syscall(SYS_RENAME, (uint64_t)oldpath, (uint64_t)newpath, 0);
+}
+
+int stat(const char *path, struct stat *st) {
+  return syscall(SYS_STAT, (uint64_t)path, (uint64_t)st, 0);
+}
+
+int lstat(const char *path, struct stat *st) {
+  return syscall(SYS_LSTAT, (uint64_t)path, (uint64_t)st, 0);
+}
+
+int sync(void) { return syscall(SYS_SYNC, 0, 0, 0); }
+
+int fsync(const char *path) { return syscall(SYS_FSYNC, (uint64_t)path, 0, 0); }
+
+int mount(const char *dev, const char *path) {
+  return syscall(SYS_MOUNT, (uint64_t)dev, (uint64_t)path, 0);
+}
+
 __attribute__((noreturn)) void exit(void) {
   syscall(SYS_EXIT, 0, 0, 0);
   for (;;)
@@ -11,4 +11,19 @@ void putchar(char ch);
 int getchar(void);
 int readfile(const char *filename, char *buf, uint64_t len);
 int writefile(const char *filename, const char *buf, uint64_t len);
+int sleep(uint64_t seconds);
+int nanosleep(const struct timespec *req);
+int clock_gettime(int clk_id, struct timespec *tp);
+int mkdir(const char *path);
+int rmdir(const char *path);
+int readdir(const char *path, char *buf, uint64_t len);
+int creat(const char *path);
+int open(const char *path, int flags);
+int unlink(const char *path);
+int rename(const char *oldpath, const char *newpath);
+int stat(const char *path, struct stat *st);
+int lstat(const char *path, struct stat *st);
+int sync(void);
+int fsync(const char *path);
+int mount(const char *dev, const char *path);
 __attribute__((noreturn)) void exit(void);
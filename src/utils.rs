@@ -29,19 +29,29 @@ pub const fn align_up(value: u64, align: u64) -> u64 {
     }
 }
 
-pub fn oct2int(oct: *const u8, len: usize) -> u32 {
+pub fn oct2int(oct: *const u8, len: usize) -> u64 {
     let mut dec = 0;
     for i in 0..len {
         unsafe {
             if *oct.add(i) < b'0' || *oct.add(i) > b'7' {
                 break;
             }
-            dec = dec * 8 + (*oct.add(i) - b'0') as u32;
+            dec = dec * 8 + (*oct.add(i) - b'0') as u64;
         }
     }
     dec
 }
 
+/// `buf` の末尾をNUL終端として、残りを0埋めの8進数文字列で埋める
+pub fn int2oct(mut value: u64, buf: &mut [u8]) {
+    let len = buf.len();
+    for i in 0..(len - 1) {
+        buf[(len - 2) - i] = (value % 8) as u8 + b'0';
+        value /= 8;
+    }
+    buf[len - 1] = b'\0';
+}
+
 pub fn ascii_len(buf: *const u8) -> usize {
     let len;
     let mut i = 0;
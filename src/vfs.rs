@@ -0,0 +1,463 @@
+use crate::{
+    block::{self, BlockDevice},
+    cmdline::CMDLINE,
+    ext2, fat32, info, tarfs, warn,
+};
+use core::ptr;
+
+pub const PATH_MAX: usize = 256;
+/// パスの1要素の最大長
+pub const NAME_MAX: usize = 255;
+const MOUNTS_MAX: usize = 8;
+/// シンボリックリンクをたどる最大の回数
+const SYMLINK_MAX: usize = 8;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFLNK: u32 = 0o120000;
+
+/// ファイルシステムの中でファイルやディレクトリを識別する番号
+pub type Ino = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+}
+
+/// `stat` システムコールで返すファイルの情報
+#[repr(C)]
+pub struct Stat {
+    pub mode: u32, // 種類 (S_IF*) とパーミッション
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    pub size: u64,
+    pub mtime: u64,
+}
+
+impl Stat {
+    pub fn file_type(&self) -> FileType {
+        match self.mode & S_IFMT {
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            _ => FileType::Regular,
+        }
+    }
+}
+
+/// ディレクトリの中の1つのエントリ
+pub struct DirEntry {
+    name: [u8; NAME_MAX],
+    name_len: usize,
+    pub kind: FileType,
+}
+
+impl DirEntry {
+    pub fn new(name: &[u8], kind: FileType) -> Result<Self, ()> {
+        if name.len() > NAME_MAX {
+            return Err(());
+        }
+        let mut entry = Self {
+            name: [0; NAME_MAX],
+            name_len: name.len(),
+            kind,
+        };
+        entry.name[0..name.len()].copy_from_slice(name);
+        Ok(entry)
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name[0..self.name_len]
+    }
+}
+
+/// ファイルシステムの実装
+///
+/// ファイルやディレクトリは `Ino` で指定する。`lookup` は `.` と `..` も扱い、
+/// ルートディレクトリの `..` はルートディレクトリ自身を返すこと
+pub trait FileSystem {
+    /// ルートディレクトリの番号
+    fn root(&self) -> Ino;
+    /// ディレクトリ `dir` の中の `name` を探す (リンクはたどらない)
+    fn lookup(&mut self, dir: Ino, name: &[u8]) -> Result<Ino, ()>;
+    fn stat(&mut self, ino: Ino) -> Result<Stat, ()>;
+    /// `offset` バイト目から読み込み、読んだバイト数を返す
+    fn read(&mut self, ino: Ino, offset: usize, buf: &mut [u8]) -> Result<usize, ()>;
+    /// `offset` バイト目から書き込み、ファイルが短ければ伸ばす
+    fn write(&mut self, ino: Ino, offset: usize, data: &[u8]) -> Result<usize, ()>;
+    /// サイズを `size` に変える。増えた部分は0で埋める
+    fn truncate(&mut self, ino: Ino, size: usize) -> Result<(), ()>;
+    /// シンボリックリンクの指す先を読み込み、その長さを返す
+    fn readlink(&mut self, ino: Ino, buf: &mut [u8]) -> Result<usize, ()>;
+    /// ディレクトリの `index` 番目のエントリ (`.` と `..` は含まない)。なければ `None`
+    fn readdir(&mut self, dir: Ino, index: usize) -> Result<Option<DirEntry>, ()>;
+    /// ディレクトリ `dir` に空のファイルかディレクトリを作る
+    fn create(&mut self, dir: Ino, name: &[u8], kind: FileType) -> Result<Ino, ()>;
+    /// ファイルやシンボリックリンクを削除する
+    fn unlink(&mut self, dir: Ino, name: &[u8]) -> Result<(), ()>;
+    /// 空のディレクトリを削除する
+    fn rmdir(&mut self, dir: Ino, name: &[u8]) -> Result<(), ()>;
+    /// `new_name` がすでにあれば置き換える
+    fn rename(
+        &mut self,
+        old_dir: Ino,
+        old_name: &[u8],
+        new_dir: Ino,
+        new_name: &[u8],
+    ) -> Result<(), ()>;
+    /// ファイルの変更をディスクに書き戻す
+    fn fsync(&mut self, ino: Ino) -> Result<(), ()>;
+    /// すべての変更をディスクに書き戻す
+    fn sync(&mut self) -> Result<(), ()>;
+}
+
+/// ブロックデバイスからファイルシステムを読み込む関数
+///
+/// そのファイルシステムでなければ `Err` を返す
+type MountFn = unsafe fn(*mut dyn BlockDevice) -> Result<&'static mut dyn FileSystem, ()>;
+
+struct FsType {
+    name: &'static str,
+    mount: MountFn,
+}
+
+/// 対応しているファイルシステム。種類を指定されなければ先頭から順に試す
+static FS_TYPES: &[FsType] = &[
+    FsType {
+        name: "fat32",
+        mount: fat32::mount,
+    },
+    FsType {
+        name: "ext2",
+        mount: ext2::mount,
+    },
+    FsType {
+        name: "tar",
+        mount: tarfs::mount,
+    },
+];
+
+/// マウントされたファイルシステムの中のファイルやディレクトリ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    mount: usize,
+    ino: Ino,
+}
+
+struct Mount {
+    /// マウントしたファイルシステム (プローブ中で場所だけ予約しているなら `None`)
+    fs: Option<*mut dyn FileSystem>,
+    /// マウント先のディレクトリ (ルートなら `None`)
+    at: Option<Node>,
+}
+
+static mut MOUNTS: [Option<Mount>; MOUNTS_MAX] = [const { None }; MOUNTS_MAX];
+
+unsafe fn fs(node: Node) -> &'static mut dyn FileSystem {
+    let mounts = &*ptr::addr_of!(MOUNTS);
+    &mut *mounts[node.mount].as_ref().unwrap().fs.unwrap()
+}
+
+unsafe fn root_node() -> Result<Node, ()> {
+    let mounts = &*ptr::addr_of!(MOUNTS);
+    match &mounts[0] {
+        Some(Mount { fs: Some(fs), .. }) => Ok(Node {
+            mount: 0,
+            ino: (**fs).root(),
+        }),
+        _ => Err(()),
+    }
+}
+
+/// `node` に別のファイルシステムがマウントされていれば、そのルートを返す
+unsafe fn cross_mounts(mut node: Node) -> Node {
+    let mounts = &*ptr::addr_of!(MOUNTS);
+    while let Some(i) = mounts.iter().position(|m| {
+        m.as_ref()
+            .is_some_and(|m| m.fs.is_some() && m.at == Some(node))
+    }) {
+        node = Node {
+            mount: i,
+            ino: (*mounts[i].as_ref().unwrap().fs.unwrap()).root(),
+        };
+    }
+    node
+}
+
+/// `node` がマウント先になっているか
+unsafe fn is_mount_point(node: Node) -> bool {
+    let mounts = &*ptr::addr_of!(MOUNTS);
+    mounts.iter().flatten().any(|mount| mount.at == Some(node))
+}
+
+/// ディレクトリ `dir` の中の `name` を、マウントポイントをまたいで探す
+unsafe fn lookup_in(mut dir: Node, name: &[u8]) -> Result<Node, ()> {
+    match name {
+        b"." => return Ok(dir),
+        b".." => {
+            // マウントされたファイルシステムのルートからはマウント先の親に戻る
+            let mounts = &*ptr::addr_of!(MOUNTS);
+            while dir.ino == fs(dir).root() {
+                match mounts[dir.mount].as_ref().unwrap().at {
+                    Some(at) => dir = at,
+                    None => return Ok(dir),
+                }
+            }
+        }
+        _ => {}
+    }
+    let ino = fs(dir).lookup(dir.ino, name)?;
+    Ok(cross_mounts(Node {
+        mount: dir.mount,
+        ino,
+    }))
+}
+
+/// `path` をルートから1要素ずつたどる
+///
+/// 途中のシンボリックリンクは常にたどり、最後の要素のシンボリックリンクは
+/// `follow` のときだけたどる
+unsafe fn resolve(path: &[u8], follow: bool) -> Result<Node, ()> {
+    // これからたどる残りのパス
+    let mut rest = [0; PATH_MAX];
+    let mut rest_len = path.len();
+    if rest_len >= PATH_MAX {
+        return Err(());
+    }
+    rest[0..rest_len].copy_from_slice(path);
+    let mut pos = 0;
+    let mut links = 0;
+    let mut node = root_node()?;
+
+    loop {
+        while pos < rest_len && rest[pos] == b'/' {
+            pos += 1;
+        }
+        if pos == rest_len {
+            return Ok(node);
+        }
+        let end = rest[pos..rest_len]
+            .iter()
+            .position(|c| *c == b'/')
+            .map_or(rest_len, |i| pos + i);
+        let last = rest[end..rest_len].iter().all(|c| *c == b'/');
+        let child = lookup_in(node, &rest[pos..end])?;
+        pos = end;
+
+        let stat = fs(child).stat(child.ino)?;
+        if stat.file_type() != FileType::Symlink || (last && !follow) {
+            node = child;
+            continue;
+        }
+
+        links += 1;
+        if links > SYMLINK_MAX {
+            return Err(());
+        }
+
+        // リンク先の後ろに残りのパスをつなげてたどり直す
+        let mut buf = [0; PATH_MAX];
+        let link_len = fs(child).readlink(child.ino, &mut buf)?;
+        let remaining = &rest[pos..rest_len];
+        let len = link_len + 1 + remaining.len();
+        if link_len == 0 || len >= PATH_MAX {
+            return Err(());
+        }
+        buf[link_len] = b'/';
+        buf[(link_len + 1)..len].copy_from_slice(remaining);
+        // 相対パスならリンクのあるディレクトリから、絶対パスならルートからたどる
+        if buf[0] == b'/' {
+            node = root_node()?;
+        }
+        rest = buf;
+        rest_len = len;
+        pos = 0;
+    }
+}
+
+/// `path` を親ディレクトリと最後の要素に分ける
+unsafe fn resolve_parent(path: &[u8]) -> Result<(Node, &[u8]), ()> {
+    let mut end = path.len();
+    while end > 0 && path[end - 1] == b'/' {
+        end -= 1;
+    }
+    let path = &path[0..end];
+    let (parent, name) = match path.iter().rposition(|c| *c == b'/') {
+        Some(i) => (&path[0..i], &path[(i + 1)..]),
+        None => (&path[0..0], path),
+    };
+    if name.is_empty() || name == b"." || name == b".." || name.len() > NAME_MAX {
+        return Err(());
+    }
+    Ok((resolve(parent, true)?, name))
+}
+
+/// `path` にマウントできるか確かめてマウント表の場所を予約し、その番号を返す
+unsafe fn reserve(path: &str) -> Result<usize, ()> {
+    let mounts = &mut *ptr::addr_of_mut!(MOUNTS);
+    let at = if mounts[0].is_none() {
+        // 最初はルートにしかマウントできない
+        if path != "/" {
+            return Err(());
+        }
+        None
+    } else {
+        let node = resolve(path.as_bytes(), true)?;
+        let stat = self::fs(node).stat(node.ino)?;
+        if stat.file_type() != FileType::Directory || is_mount_point(node) {
+            return Err(());
+        }
+        Some(node)
+    };
+
+    let i = mounts.iter().position(|slot| slot.is_none()).ok_or(())?;
+    mounts[i] = Some(Mount { fs: None, at });
+    Ok(i)
+}
+
+/// ブロックデバイス `dev` のファイルシステムを `path` にマウントする
+///
+/// `fs_type` が空なら対応しているファイルシステムを順に試す
+pub unsafe fn mount_device(dev: &str, path: &str, fs_type: &str) -> Result<(), ()> {
+    let device = match block::lookup(dev) {
+        Some(device) => device as *mut dyn BlockDevice,
+        None => {
+            warn!("vfs: block device not found: {dev}");
+            return Err(());
+        }
+    };
+
+    // ファイルシステムを見つけてからマウントできないと分かっても戻せないので、
+    // マウント先を先に確かめて場所を予約しておく
+    let Ok(i) = reserve(path) else {
+        warn!("vfs: cannot mount on {path}");
+        return Err(());
+    };
+    let mounts = &mut *ptr::addr_of_mut!(MOUNTS);
+    for t in FS_TYPES
+        .iter()
+        .filter(|t| fs_type.is_empty() || t.name == fs_type)
+    {
+        if let Ok(fs) = (t.mount)(device) {
+            mounts[i].as_mut().unwrap().fs = Some(fs);
+            info!("vfs: mounted {dev} ({}) on {path}", t.name);
+            return Ok(());
+        }
+    }
+    mounts[i] = None;
+    warn!("vfs: no filesystem found on {dev}");
+    Err(())
+}
+
+/// `root=` で指定されたブロックデバイスをルートにマウントする
+pub unsafe fn init() {
+    let root = CMDLINE.root();
+    if mount_device(root, "/", "").is_err() {
+        panic!("vfs: failed to mount root filesystem from {root}");
+    }
+}
+
+/// `path` にあるファイルかディレクトリを、リンクをたどって探す
+pub unsafe fn lookup(path: &str) -> Result<Node, ()> {
+    resolve(path.as_bytes(), true)
+}
+
+/// `follow` でなければ最後のシンボリックリンクそのものの情報を返す
+pub unsafe fn stat(path: &str, follow: bool) -> Result<Stat, ()> {
+    let node = resolve(path.as_bytes(), follow)?;
+    fs(node).stat(node.ino)
+}
+
+pub unsafe fn stat_node(node: Node) -> Result<Stat, ()> {
+    fs(node).stat(node.ino)
+}
+
+pub unsafe fn read(node: Node, offset: usize, buf: &mut [u8]) -> Result<usize, ()> {
+    fs(node).read(node.ino, offset, buf)
+}
+
+pub unsafe fn write(node: Node, offset: usize, data: &[u8]) -> Result<usize, ()> {
+    fs(node).write(node.ino, offset, data)
+}
+
+pub unsafe fn truncate(node: Node, size: usize) -> Result<(), ()> {
+    fs(node).truncate(node.ino, size)
+}
+
+/// ディレクトリ `path` の `index` 番目のエントリ
+pub unsafe fn readdir(path: &str, index: usize) -> Result<Option<DirEntry>, ()> {
+    let dir = lookup(path)?;
+    fs(dir).readdir(dir.ino, index)
+}
+
+pub unsafe fn create(path: &str) -> Result<Node, ()> {
+    let (dir, name) = resolve_parent(path.as_bytes())?;
+    let ino = fs(dir).create(dir.ino, name, FileType::Regular)?;
+    Ok(Node {
+        mount: dir.mount,
+        ino,
+    })
+}
+
+pub unsafe fn mkdir(path: &str) -> Result<(), ()> {
+    let (dir, name) = resolve_parent(path.as_bytes())?;
+    fs(dir)
+        .create(dir.ino, name, FileType::Directory)
+        .map(|_| ())
+}
+
+/// マウント先になっているものは削除できない
+unsafe fn check_removable(dir: Node, name: &[u8]) -> Result<(), ()> {
+    let ino = fs(dir).lookup(dir.ino, name)?;
+    if is_mount_point(Node {
+        mount: dir.mount,
+        ino,
+    }) {
+        return Err(());
+    }
+    Ok(())
+}
+
+pub unsafe fn unlink(path: &str) -> Result<(), ()> {
+    let (dir, name) = resolve_parent(path.as_bytes())?;
+    fs(dir).unlink(dir.ino, name)
+}
+
+pub unsafe fn rmdir(path: &str) -> Result<(), ()> {
+    let (dir, name) = resolve_parent(path.as_bytes())?;
+    check_removable(dir, name)?;
+    fs(dir).rmdir(dir.ino, name)
+}
+
+/// 同じファイルシステムの中でだけ名前を変えられる
+pub unsafe fn rename(old: &str, new: &str) -> Result<(), ()> {
+    let (old_dir, old_name) = resolve_parent(old.as_bytes())?;
+    let (new_dir, new_name) = resolve_parent(new.as_bytes())?;
+    if old_dir.mount != new_dir.mount {
+        return Err(());
+    }
+    check_removable(old_dir, old_name)?;
+    if fs(new_dir).lookup(new_dir.ino, new_name).is_ok() {
+        check_removable(new_dir, new_name)?;
+    }
+    fs(old_dir).rename(old_dir.ino, old_name, new_dir.ino, new_name)
+}
+
+pub unsafe fn fsync(node: Node) -> Result<(), ()> {
+    fs(node).fsync(node.ino)
+}
+
+/// マウントされているすべてのファイルシステムの変更を書き戻す
+pub unsafe fn sync() -> Result<(), ()> {
+    let mounts = &*ptr::addr_of!(MOUNTS);
+    let mut result = Ok(());
+    for fs in mounts.iter().flatten().filter_map(|mount| mount.fs) {
+        if (*fs).sync().is_err() {
+            result = Err(());
+        }
+    }
+    result
+}
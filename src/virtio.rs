@@ -0,0 +1,237 @@
+use crate::{
+    fdt::PLATFORM,
+    info,
+    memory::{alloc_pages, PAGE_SIZE},
+    paging::register_mmio,
+    types::PhysAddr,
+    utils::align_up,
+    virtio_blk,
+    virtio_mmio::{Transport, VIRTIO_STATUS_ACK, VIRTIO_STATUS_DRIVER, VIRTIO_STATUS_FAILED},
+    warn,
+};
+use core::{
+    mem,
+    sync::atomic::{compiler_fence, Ordering::SeqCst},
+};
+
+pub const VIRTQ_ENTRY_NUM: usize = 16;
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+// const VIRTQ_AVAIL_F_NO_INTERRUPT: u64 = 1;
+
+pub const VIRTIO_DEVICE_BLK: u32 = 2;
+
+/// QEMU virtのvirtio-mmioのスロット (デバイスツリーがない場合に使う)
+const DEFAULT_VIRTIO_MMIO_PADDR: u64 = 0x1000_1000;
+const DEFAULT_VIRTIO_MMIO_STRIDE: u64 = 0x1000;
+const DEFAULT_VIRTIO_MMIO_NUM: u32 = 8;
+const DEFAULT_VIRTIO_MMIO_IRQ: u32 = 1;
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct VirtqDesc {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct VirtqAvail {
+    flags: u16,
+    idx: u16,
+    ring: [u16; VIRTQ_ENTRY_NUM],
+    used_event: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct VirtqUsed {
+    flags: u16,
+    idx: u16,
+    ring: [VirtqUsedElem; VIRTQ_ENTRY_NUM],
+    avail_event: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Debug)]
+pub struct Virtq {
+    pub desc: [VirtqDesc; VIRTQ_ENTRY_NUM], // size(0x100), align(0x1000)
+    avail: VirtqAvail,                      // size(0x26)
+    pad: [u8; 0xeda],                       // size(0x1000 - (0x100 + 0x26) = 0xeda)
+    used: VirtqUsed,                        // size(_), align(0x1000)
+
+    transport: Transport,
+    queue_idx: u32,
+    used_idx: *mut u16,
+    last_used_idx: u16,
+    free_head: u16, // 空きディスクリプタのリストの先頭 (`next` でつながっている)
+    num_free: u16,
+}
+
+impl Virtq {
+    /// virtqueueを確保して `transport` の `index` 番目のキューとして登録する
+    pub unsafe fn init(transport: Transport, index: u32) -> Result<*mut Self, ()> {
+        let virtq_paddr =
+            alloc_pages(align_up(mem::size_of::<Virtq>() as u64, PAGE_SIZE) / PAGE_SIZE);
+        let vq = virtq_paddr.as_u64() as *mut Virtq;
+        let virtq = vq.as_mut().unwrap();
+        virtq.transport = transport;
+        virtq.queue_idx = index;
+        let used_idx = (&mut (virtq.used) as *const VirtqUsed as *const u8)
+            .offset(mem::offset_of!(VirtqUsed, idx) as isize);
+        virtq.used_idx = used_idx as *mut u16;
+
+        // すべてのディスクリプタを空きリストにつなぐ
+        for i in 0..VIRTQ_ENTRY_NUM {
+            virtq.desc[i].next = (i + 1) as u16;
+        }
+        virtq.free_head = 0;
+        virtq.num_free = VIRTQ_ENTRY_NUM as u16;
+
+        let avail_paddr = virtq_paddr + PhysAddr::new(mem::offset_of!(Virtq, avail) as u64);
+        let used_paddr = virtq_paddr + PhysAddr::new(mem::offset_of!(Virtq, used) as u64);
+        transport.setup_queue(
+            index,
+            VIRTQ_ENTRY_NUM as u32,
+            virtq_paddr,
+            avail_paddr,
+            used_paddr,
+        )?;
+
+        Ok(vq)
+    }
+
+    /// 空きディスクリプタを `n` 個取り出して `next` でつなぎ、先頭のインデックスを返す
+    pub fn alloc_chain(&mut self, n: usize) -> Option<u16> {
+        if n == 0 || (self.num_free as usize) < n {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut tail = head;
+        for _ in 1..n {
+            tail = self.desc[tail as usize].next;
+        }
+        self.free_head = self.desc[tail as usize].next;
+        self.num_free -= n as u16;
+        Some(head)
+    }
+
+    /// `head` から始まるディスクリプタのチェーンを空きリストに戻す
+    pub fn free_chain(&mut self, head: u16) {
+        let mut tail = head;
+        let mut n = 1;
+        while self.desc[tail as usize].flags & VIRTQ_DESC_F_NEXT != 0 {
+            tail = self.desc[tail as usize].next;
+            n += 1;
+        }
+        self.desc[tail as usize].next = self.free_head;
+        self.free_head = head;
+        self.num_free += n;
+    }
+
+    pub fn kick(vq: *mut Virtq, desc_index: u32) {
+        let vq = unsafe { vq.as_mut().unwrap() };
+
+        vq.avail.ring[vq.avail.idx as usize % VIRTQ_ENTRY_NUM] = desc_index as u16;
+        compiler_fence(SeqCst);
+        vq.avail.idx = vq.avail.idx.wrapping_add(1);
+        compiler_fence(SeqCst);
+        unsafe {
+            let transport = vq.transport;
+            transport.notify(vq.queue_idx);
+        }
+    }
+
+    /// デバイスが処理を終えたチェーンの先頭インデックスを1つ取り出す
+    pub fn pop_used(&mut self) -> Option<u16> {
+        // デバイスが書き換えるので毎回メモリから読む
+        if self.last_used_idx == unsafe { self.used_idx.read_volatile() } {
+            return None;
+        }
+        compiler_fence(SeqCst);
+        let elem = self.used.ring[self.last_used_idx as usize % VIRTQ_ENTRY_NUM];
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        Some(elem.id as u16)
+    }
+}
+
+/// デバイスIDごとのドライバ
+struct Driver {
+    device_id: u32,
+    name: &'static str,
+    /// 初期化の途中 (DRIVERステータスまで設定済み) のデバイスを受け取って初期化する
+    probe: unsafe fn(Transport, u32) -> Result<(), ()>,
+}
+
+const DRIVERS: &[Driver] = &[Driver {
+    device_id: VIRTIO_DEVICE_BLK,
+    name: "virtio-blk",
+    probe: virtio_blk::probe,
+}];
+
+/// virtio-mmioのスロットを走査して、見つかったデバイスにドライバを割り当てる
+pub unsafe fn init() {
+    let platform = &PLATFORM;
+    if platform.virtio_mmio_len > 0 {
+        for i in 0..platform.virtio_mmio_len {
+            let dev = platform.virtio_mmio[i];
+            probe_slot(dev.reg.base, dev.irq);
+        }
+    } else {
+        for i in 0..DEFAULT_VIRTIO_MMIO_NUM {
+            let base = DEFAULT_VIRTIO_MMIO_PADDR + i as u64 * DEFAULT_VIRTIO_MMIO_STRIDE;
+            probe_slot(PhysAddr::new(base), DEFAULT_VIRTIO_MMIO_IRQ + i);
+        }
+    }
+}
+
+unsafe fn probe_slot(base: PhysAddr, irq: u32) {
+    let transport = match Transport::probe(base) {
+        Some(transport) => transport,
+        None => return,
+    };
+    // デバイスIDが0のスロットには何も接続されていない
+    let device_id = transport.device_id();
+    if device_id == 0 {
+        return;
+    }
+
+    let driver = match DRIVERS.iter().find(|driver| driver.device_id == device_id) {
+        Some(driver) => driver,
+        None => {
+            info!(
+                "virtio: {:#x}: no driver for device id {device_id}",
+                base.as_u64()
+            );
+            return;
+        }
+    };
+    register_mmio(base, PAGE_SIZE);
+
+    // 1. Reset the device.
+    transport.reset();
+    // 2. Set the ACKNOWLEDGE status bit: the guest OS has noticed the device.
+    transport.add_status(VIRTIO_STATUS_ACK);
+    // 3. Set the DRIVER status bit: the guest OS knows how to drive the device.
+    transport.add_status(VIRTIO_STATUS_DRIVER);
+
+    if (driver.probe)(transport, irq).is_err() {
+        warn!(
+            "{}: {:#x}: failed to initialize",
+            driver.name,
+            base.as_u64()
+        );
+        // デバイスを使わないことを伝える
+        transport.add_status(VIRTIO_STATUS_FAILED);
+    }
+}
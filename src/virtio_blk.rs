@@ -1,254 +1,547 @@
 use crate::{
+    block::{self, BlockDevice, SECTOR_SIZE},
+    handler::register_irq,
+    info,
     memory::{alloc_pages, PAGE_SIZE},
-    println,
+    process,
+    timer::{self, Deadline},
     types::PhysAddr,
     utils::align_up,
+    virtio::{Virtq, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE, VIRTQ_ENTRY_NUM},
+    virtio_mmio::{Transport, VIRTIO_STATUS_DRIVER_OK},
+    warn,
 };
-use core::{
-    mem, ptr,
-    sync::atomic::{compiler_fence, Ordering::SeqCst},
-};
-
-pub const SECTOR_SIZE: u32 = 512;
-const VIRTQ_ENTRY_NUM: usize = 16;
-const VIRTIO_DEVICE_BLK: u32 = 2;
-pub const VIRTIO_BLK_PADDR: PhysAddr = PhysAddr::new(0x1000_1000);
-const VIRTIO_REG_MAGIC: u64 = 0x00;
-const VIRTIO_REG_VERSION: u64 = 0x04;
-const VIRTIO_REG_DEVICE_ID: u64 = 0x08;
-const VIRTIO_REG_QUEUE_SEL: u64 = 0x30;
-// const VIRTIO_REG_QUEUE_NUM_MAX: u64 = 0x34;
-const VIRTIO_REG_QUEUE_NUM: u64 = 0x38;
-const VIRTIO_REG_QUEUE_ALIGN: u64 = 0x3c;
-const VIRTIO_REG_QUEUE_PFN: u64 = 0x40;
-// const VIRTIO_REG_QUEUE_READY: u64 = 0x44;
-const VIRTIO_REG_QUEUE_NOTIFY: u64 = 0x50;
-const VIRTIO_REG_DEVICE_STATUS: u64 = 0x70;
-const VIRTIO_REG_DEVICE_CONFIG: u64 = 0x100;
-const VIRTIO_STATUS_ACK: u32 = 1;
-const VIRTIO_STATUS_DRIVER: u32 = 2;
-const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
-const VIRTIO_STATUS_FEAT_OK: u32 = 8;
-const VIRTQ_DESC_F_NEXT: u16 = 1;
-const VIRTQ_DESC_F_WRITE: u16 = 2;
-// const VIRTQ_AVAIL_F_NO_INTERRUPT: u64 = 1;
-const VIRTIO_BLK_T_IN: u64 = 0;
-const VIRTIO_BLK_T_OUT: u64 = 1;
-
+use core::{mem, ptr};
+
+/// 接続できるvirtio-blkデバイスの最大数
+const VIRTIO_BLK_MAX: usize = 4;
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
+const VIRTIO_BLK_TIMEOUT_MS: u64 = 1000;
+const VIRTIO_BLK_F_SIZE_MAX: u64 = 1 << 1;
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1 << 14;
+/// このドライバが対応している機能ビット
+const VIRTIO_BLK_SUPPORTED_FEATURES: u64 = VIRTIO_BLK_F_SIZE_MAX
+    | VIRTIO_BLK_F_SEG_MAX
+    | VIRTIO_BLK_F_RO
+    | VIRTIO_BLK_F_BLK_SIZE
+    | VIRTIO_BLK_F_FLUSH
+    | VIRTIO_BLK_F_DISCARD
+    | VIRTIO_BLK_F_WRITE_ZEROES;
+/// WRITE_ZEROESに対応していないデバイスで使う0のバッファ
+const ZEROES_SIZE: usize = 4096;
+
+/// `struct virtio_blk_config` のフィールドのオフセット
+const VIRTIO_BLK_CONFIG_CAPACITY: u64 = 0;
+const VIRTIO_BLK_CONFIG_SIZE_MAX: u64 = 8;
+const VIRTIO_BLK_CONFIG_SEG_MAX: u64 = 12;
+const VIRTIO_BLK_CONFIG_BLK_SIZE: u64 = 20;
+const VIRTIO_BLK_CONFIG_MAX_DISCARD_SECTORS: u64 = 36;
+const VIRTIO_BLK_CONFIG_MAX_WRITE_ZEROES_SECTORS: u64 = 48;
+const VIRTIO_BLK_CONFIG_WRITE_ZEROES_MAY_UNMAP: u64 = 56;
+
+/// チェーンの先頭ディスクリプタごとに用意するリクエストの領域
+/// (データは呼び出し元のバッファを直接ディスクリプタで指す)
 #[repr(C, packed)]
-#[derive(Debug, Clone, Copy)]
-struct VirtqDesc {
-    addr: u64,
-    len: u32,
-    flags: u16,
-    next: u16,
+#[derive(Debug)]
+struct VirtioBlkReq {
+    type_: u32,
+    reserved: u32,
+    sector: u64,
+    range: VirtioBlkDiscardWriteZeroes,
+    status: u8,
+    done: bool, // デバイスが処理を終えたか (ドライバだけが使う)
 }
 
+/// DISCARDとWRITE_ZEROESで対象の範囲を指定するデータ
 #[repr(C, packed)]
 #[derive(Debug, Clone, Copy)]
-struct VirtqAvail {
-    flags: u16,
-    idx: u16,
-    ring: [u16; VIRTQ_ENTRY_NUM],
-    used_event: u16,
+struct VirtioBlkDiscardWriteZeroes {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
 }
 
-#[repr(C, packed)]
+/// デバイス固有の設定領域から読み取ったデバイスの情報
 #[derive(Debug, Clone, Copy)]
-struct VirtqUsedElem {
-    id: u32,
-    len: u32,
+struct BlkConfig {
+    capacity: u64, // セクタ数
+    size_max: u32, // 1つのディスクリプタで転送できる最大バイト数 (0なら制限なし)
+    seg_max: u32,  // 1つのリクエストに含められる最大データディスクリプタ数 (0なら制限なし)
+    blk_size: u32, // 論理ブロックサイズ
+    read_only: bool,
+    max_discard_sectors: u32,      // 0なら制限なし
+    max_write_zeroes_sectors: u32, // 0なら制限なし
+    write_zeroes_may_unmap: bool,
 }
 
-#[repr(C, packed)]
+impl BlkConfig {
+    const fn new() -> Self {
+        Self {
+            capacity: 0,
+            size_max: 0,
+            seg_max: 0,
+            blk_size: SECTOR_SIZE,
+            read_only: false,
+            max_discard_sectors: 0,
+            max_write_zeroes_sectors: 0,
+            write_zeroes_may_unmap: false,
+        }
+    }
+
+    unsafe fn read(transport: &Transport, features: u64) -> Self {
+        let mut config = Self::new();
+        config.capacity = transport.config_read64(VIRTIO_BLK_CONFIG_CAPACITY);
+        if features & VIRTIO_BLK_F_SIZE_MAX != 0 {
+            config.size_max = transport.config_read32(VIRTIO_BLK_CONFIG_SIZE_MAX);
+        }
+        if features & VIRTIO_BLK_F_SEG_MAX != 0 {
+            config.seg_max = transport.config_read32(VIRTIO_BLK_CONFIG_SEG_MAX);
+        }
+        if features & VIRTIO_BLK_F_BLK_SIZE != 0 {
+            config.blk_size = transport.config_read32(VIRTIO_BLK_CONFIG_BLK_SIZE);
+        }
+        config.read_only = features & VIRTIO_BLK_F_RO != 0;
+        if features & VIRTIO_BLK_F_DISCARD != 0 {
+            config.max_discard_sectors =
+                transport.config_read32(VIRTIO_BLK_CONFIG_MAX_DISCARD_SECTORS);
+        }
+        if features & VIRTIO_BLK_F_WRITE_ZEROES != 0 {
+            config.max_write_zeroes_sectors =
+                transport.config_read32(VIRTIO_BLK_CONFIG_MAX_WRITE_ZEROES_SECTORS);
+            config.write_zeroes_may_unmap =
+                transport.config_read8(VIRTIO_BLK_CONFIG_WRITE_ZEROES_MAY_UNMAP) != 0;
+        }
+        config
+    }
+
+    /// `len` バイトのバッファに必要なデータディスクリプタの数
+    fn descs_for(&self, len: usize) -> usize {
+        if self.size_max == 0 {
+            1
+        } else {
+            len.div_ceil(self.size_max as usize)
+        }
+    }
+}
+
+/// スキャッタギャザーIOのバッファ1つ分 (カーネルの物理アドレス)
 #[derive(Debug, Clone, Copy)]
-struct VirtqUsed {
-    flags: u16,
-    idx: u16,
-    ring: [VirtqUsedElem; VIRTQ_ENTRY_NUM],
-    avail_event: u16,
+pub struct Segment {
+    pub addr: *mut u8,
+    pub len: usize,
 }
 
-#[repr(C, packed)]
-#[derive(Debug)]
-pub struct Virtq {
-    desc: [VirtqDesc; VIRTQ_ENTRY_NUM], // size(0x100), align(0x1000)
-    avail: VirtqAvail,                  // size(0x26)
-    pad: [u8; 0xeda],                   // size(0x1000 - (0x100 + 0x26) = 0xeda)
-    used: VirtqUsed,                    // size(_), align(0x1000)
-
-    queue_idx: u32,
-    used_idx: *mut u16,
-    last_used_idx: u16,
+/// virtio-blkデバイス1台分の状態
+pub struct VirtioBlk {
+    transport: Transport,
+    irq: u32,
+    vq: *mut Virtq,
+    reqs: *mut [VirtioBlkReq; VIRTQ_ENTRY_NUM],
+    reqs_paddr: PhysAddr,
+    config: BlkConfig,
+    features: u64,
+    /// タイムアウトしたのでデバイスをリセットしたか (以降のリクエストはすべて失敗させる)
+    broken: bool,
 }
 
-impl Virtq {
-    unsafe fn init(index: u32) -> *mut Self {
-        let virtq_paddr =
-            alloc_pages(align_up(mem::size_of::<Virtq>() as u64, PAGE_SIZE) / PAGE_SIZE);
-        let vq = virtq_paddr.as_u64() as *mut Virtq;
-        let virtq = vq.as_mut().unwrap();
-        virtq.queue_idx = index;
-        let used_idx = (&mut (virtq.used) as *const VirtqUsed as *const u8)
-            .offset(mem::offset_of!(VirtqUsed, idx) as isize);
-        virtq.used_idx = used_idx as *mut u16;
+static mut BLK_DEVICES: [VirtioBlk; VIRTIO_BLK_MAX] = [const { VirtioBlk::new() }; VIRTIO_BLK_MAX];
+static mut BLK_DEVICES_LEN: usize = 0;
+static mut ZEROES: [u8; ZEROES_SIZE] = [0; ZEROES_SIZE];
 
-        // 1. Select the queue writing its index (first queue is 0) to QueueSel.
-        reg_write32(VIRTIO_REG_QUEUE_SEL, index);
-        // 5. Notify the device about the queue size by writing the size to QueueNum.
-        reg_write32(VIRTIO_REG_QUEUE_NUM, VIRTQ_ENTRY_NUM as u32);
-        // 6. Notify the device about the used alignment by writing its value in bytes to QueueAlign.
-        reg_write32(VIRTIO_REG_QUEUE_ALIGN, 0);
-        // 7. Write the physical number of the first page of the queue to the QueuePFN register.
-        reg_write32(VIRTIO_REG_QUEUE_PFN, virtq_paddr.as_u64() as u32);
+/// virtioバスから呼ばれ、DRIVERステータスまで設定されたデバイスを初期化する
+pub unsafe fn probe(transport: Transport, irq: u32) -> Result<(), ()> {
+    if BLK_DEVICES_LEN == VIRTIO_BLK_MAX {
+        warn!("virtio-blk: too many devices");
+        return Err(());
+    }
 
-        vq
+    // 4.-6. 機能ビットをネゴシエートする
+    let features = transport.negotiate_features(VIRTIO_BLK_SUPPORTED_FEATURES)?;
+    // 7. Perform device-specific setup, including discovery of virtqueues for the device
+    let vq = Virtq::init(transport, 0)?;
+    // 8. Set the DRIVER_OK status bit.
+    transport.add_status(VIRTIO_STATUS_DRIVER_OK);
+
+    // デバイスへの処理要求を格納する領域をディスクリプタの数だけ確保
+    let reqs_paddr = alloc_pages(
+        align_up(
+            mem::size_of::<[VirtioBlkReq; VIRTQ_ENTRY_NUM]>() as u64,
+            PAGE_SIZE,
+        ) / PAGE_SIZE,
+    );
+
+    // ディスク容量などを取得
+    let config = BlkConfig::read(&transport, features);
+    let index = BLK_DEVICES_LEN;
+    BLK_DEVICES[index] = VirtioBlk {
+        transport,
+        irq,
+        vq,
+        reqs: reqs_paddr.as_u64() as *mut [VirtioBlkReq; VIRTQ_ENTRY_NUM],
+        reqs_paddr,
+        config,
+        features,
+        broken: false,
+    };
+    BLK_DEVICES_LEN += 1;
+
+    // vda, vdb, ... という名前で登録する
+    let name = [b'v', b'd', b'a' + index as u8];
+    let name = core::str::from_utf8(&name).unwrap();
+    if block::register(name, &mut *ptr::addr_of_mut!(BLK_DEVICES[index])).is_err() {
+        warn!("virtio-blk{index}: failed to register {name}");
+    }
+    info!(
+        "virtio-blk{index}: base={:#x}, irq={irq}, capacity is {} bytes, block size is {}{}",
+        transport.base().as_u64(),
+        config.capacity * SECTOR_SIZE as u64,
+        config.blk_size,
+        if config.read_only { ", read-only" } else { "" }
+    );
+
+    // 割り込み番号を共有しているデバイスがあればハンドラは登録済み
+    let shared = (0..index).any(|i| BLK_DEVICES[i].irq == irq);
+    if !shared && register_irq(irq, handle_irq).is_err() {
+        warn!("virtio-blk{index}: failed to register irq={irq}");
     }
+    Ok(())
+}
 
-    fn kick(vq: *mut Virtq, desc_index: u32) {
-        let vq = unsafe { vq.as_mut().unwrap() };
+fn handle_irq(irq: u32) {
+    unsafe {
+        for i in 0..BLK_DEVICES_LEN {
+            let dev = &mut *ptr::addr_of_mut!(BLK_DEVICES[i]);
+            if dev.irq == irq {
+                dev.transport.ack_interrupt();
+                dev.process_used();
+            }
+        }
+    }
+}
 
-        vq.avail.ring[vq.avail.idx as usize % VIRTQ_ENTRY_NUM] = desc_index as u16;
-        vq.avail.idx += 1;
-        compiler_fence(SeqCst);
-        unsafe {
-            reg_write32(VIRTIO_REG_QUEUE_NOTIFY, vq.queue_idx);
+impl VirtioBlk {
+    const fn new() -> Self {
+        Self {
+            transport: Transport::new(PhysAddr::new(0)),
+            irq: 0,
+            vq: ptr::null_mut(),
+            reqs: ptr::null_mut(),
+            reqs_paddr: PhysAddr::new(0),
+            config: BlkConfig::new(),
+            features: 0,
+            broken: false,
         }
-        vq.last_used_idx += 1;
     }
 
-    fn is_busy(&self) -> bool {
-        // self.last_used_idx != unsafe { *self.used_idx }
-        self.last_used_idx != self.used.idx
+    /// 処理が終わったリクエストに完了の印を付けて、待っているプロセスを起こす
+    unsafe fn process_used(&mut self) {
+        let vq = self.vq.as_mut().unwrap();
+        while let Some(head) = vq.pop_used() {
+            (*self.reqs)[head as usize].done = true;
+            process::wakeup(self.request_chan(head));
+        }
     }
-}
 
-#[repr(C, packed)]
-#[derive(Debug)]
-struct VirtioBlkReq {
-    type_: u32,
-    reserved: u32,
-    sector: u64,
-    data: [u8; 512],
-    status: u8,
-}
+    fn request_chan(&self, head: u16) -> u64 {
+        unsafe { &(*self.reqs)[head as usize] as *const VirtioBlkReq as u64 }
+    }
 
-// unsafe fn reg_read8(offset: u64) -> u8 {
-//     ((VIRTIO_BLK_PADDR + offset) as *const u8).read_volatile()
-// }
+    fn free_desc_chan(&self) -> u64 {
+        self.vq as u64
+    }
 
-unsafe fn reg_read32(offset: u64) -> u32 {
-    ((VIRTIO_BLK_PADDR.as_u64() + offset) as *const u32).read_volatile()
-}
+    /// デバイスをリセットして、処理中のリクエストをすべて失敗させる
+    ///
+    /// タイムアウトしたリクエストのバッファにあとからデバイスが書き込まないように、
+    /// デバイスを止めてから待っているプロセスを起こす
+    unsafe fn reset(&mut self) {
+        self.transport.reset();
+        self.broken = true;
+        for head in 0..VIRTQ_ENTRY_NUM {
+            let req = &mut (*self.reqs)[head];
+            if !req.done {
+                // ステータスは発行時の0xffのままなので、待っていたプロセスには失敗として見える
+                req.done = true;
+                process::wakeup(self.request_chan(head as u16));
+            }
+        }
+        process::wakeup(self.free_desc_chan());
+    }
 
-unsafe fn reg_read64(offset: u64) -> u64 {
-    ((VIRTIO_BLK_PADDR.as_u64() + offset) as *const u64).read_volatile()
-}
+    /// リクエストの完了を待つ。プロセスから呼ばれた場合は割り込みが来るまでブロックする
+    unsafe fn wait_for_completion(&mut self, head: u16) -> Result<(), ()> {
+        let deadline = Deadline::after_ms(VIRTIO_BLK_TIMEOUT_MS);
+        while !(*self.reqs)[head as usize].done {
+            if deadline.expired() {
+                return Err(());
+            }
+            if process::can_block() {
+                timer::block_until(self.request_chan(head), deadline);
+            } else {
+                self.process_used();
+            }
+        }
+        Ok(())
+    }
 
-unsafe fn reg_write32(offset: u64, value: u32) {
-    ((VIRTIO_BLK_PADDR.as_u64() + offset) as *mut u32).write_volatile(value);
-}
+    /// 連続したセクタを複数のバッファに対して読み書きする
+    pub unsafe fn read_write_sg(
+        &mut self,
+        segments: &[Segment],
+        sector: u64,
+        is_write: bool,
+    ) -> Result<(), ()> {
+        let len = segments.iter().fold(0, |sum, seg| sum + seg.len);
+        let count = (len / SECTOR_SIZE as usize) as u64;
+        if len == 0 || len % SECTOR_SIZE as usize != 0 {
+            warn!("virtio: invalid request length: {len}");
+            return Err(());
+        }
+        if sector + count > self.capacity_sectors() {
+            warn!(
+                "virtio: tried to read/write sector={sector}..{}, but capacity is {}",
+                sector + count,
+                self.capacity_sectors()
+            );
+            return Err(());
+        }
 
-unsafe fn reg_fetch_and_or32(offset: u64, value: u32) {
-    reg_write32(offset, reg_read32(offset) | value);
-}
+        if is_write && self.is_read_only() {
+            warn!("virtio: tried to write sector={sector} to read-only device");
+            return Err(());
+        }
 
-static mut BLK_REQUEST_VQ: *mut Virtq = ptr::null_mut();
-static mut BLK_REQ: *mut VirtioBlkReq = ptr::null_mut();
-static mut BLK_REQ_PADDR: PhysAddr = PhysAddr::new(0);
-static mut BLK_CAPACITY: u32 = 0;
-
-pub unsafe fn init() {
-    assert!(reg_read32(VIRTIO_REG_MAGIC) == 0x74726976);
-    assert!(reg_read32(VIRTIO_REG_VERSION) == 1);
-    assert!(reg_read32(VIRTIO_REG_DEVICE_ID) == VIRTIO_DEVICE_BLK);
-
-    // 1. Reset the device.
-    reg_write32(VIRTIO_REG_DEVICE_STATUS, 0);
-    // 2. Set the ACKNOWLEDGE status bit: the guest OS has noticed the device.
-    reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_ACK);
-    // 3. Set the DRIVER status bit: the guest OS knows how to drive the device.
-    reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER);
-    // 5. Set the FEATURES_OK status bit.
-    reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_FEAT_OK);
-    // 7. Perform device-specific setup, including discovery of virtqueues for the device
-    BLK_REQUEST_VQ = Virtq::init(0);
-    // 8. Set the DRIVER_OK status bit.
-    reg_write32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER_OK);
+        let type_ = if is_write {
+            VIRTIO_BLK_T_OUT
+        } else {
+            VIRTIO_BLK_T_IN
+        };
+        self.submit(type_, sector, segments, None)
+    }
+
+    /// DISCARDやWRITE_ZEROESを1リクエストあたり `max` セクタ (0なら無制限) に分けて発行する
+    unsafe fn submit_ranges(
+        &mut self,
+        type_: u32,
+        sector: u64,
+        count: u64,
+        max: u32,
+        flags: u32,
+    ) -> Result<(), ()> {
+        if self.is_read_only() || sector + count > self.capacity_sectors() {
+            return Err(());
+        }
+
+        let max = if max == 0 {
+            u32::MAX as u64
+        } else {
+            max as u64
+        };
+        let mut off = 0;
+        while off < count {
+            let n = (count - off).min(max);
+            let range = VirtioBlkDiscardWriteZeroes {
+                sector: sector + off,
+                num_sectors: n as u32,
+                flags,
+            };
+            self.submit(type_, 0, &[], Some(range))?;
+            off += n;
+        }
+        Ok(())
+    }
 
-    // ディスク容量を取得
-    BLK_CAPACITY = reg_read64(VIRTIO_REG_DEVICE_CONFIG + 0) as u32 * SECTOR_SIZE;
-    println!("virtio-blk: capacity is {BLK_CAPACITY} bytes");
+    /// リクエストを発行して完了を待つ
+    ///
+    /// `segments` はデータのバッファ、`range` はDISCARDやWRITE_ZEROESの対象範囲
+    unsafe fn submit(
+        &mut self,
+        type_: u32,
+        sector: u64,
+        segments: &[Segment],
+        range: Option<VirtioBlkDiscardWriteZeroes>,
+    ) -> Result<(), ()> {
+        if self.broken {
+            return Err(());
+        }
+
+        // デバイスがデータを書き込むのは読み込み要求の場合だけ
+        let device_writes = type_ == VIRTIO_BLK_T_IN;
+
+        // ヘッダ、データ、ステータスの分のディスクリプタが空くまで待つ
+        let config = self.config;
+        let data_descs = segments
+            .iter()
+            .fold(0, |sum, seg| sum + config.descs_for(seg.len))
+            + if range.is_some() { 1 } else { 0 };
+        let ndesc = data_descs + 2;
+        if ndesc > VIRTQ_ENTRY_NUM || (config.seg_max != 0 && data_descs > config.seg_max as usize)
+        {
+            warn!("virtio: too many segments: {}", segments.len());
+            return Err(());
+        }
+        let vq = self.vq.as_mut().unwrap();
+        let head = loop {
+            if self.broken {
+                return Err(());
+            }
+            if let Some(head) = vq.alloc_chain(ndesc) {
+                break head;
+            }
+            if process::can_block() {
+                process::block(self.free_desc_chan());
+            } else {
+                self.process_used();
+            }
+        };
+
+        // リクエストを構築する
+        let blk_req = &mut (*self.reqs)[head as usize];
+        let blk_req_paddr =
+            self.reqs_paddr.as_u64() + (head as usize * mem::size_of::<VirtioBlkReq>()) as u64;
+        blk_req.sector = sector;
+        blk_req.type_ = type_;
+        blk_req.status = 0xff;
+        blk_req.done = false;
+
+        // virtqueueのディスクリプタを構築する
+        let desc = &mut vq.desc[head as usize];
+        desc.addr = blk_req_paddr;
+        desc.len = (mem::size_of::<u32>() * 2 + mem::size_of::<u64>()) as u32;
+        desc.flags = VIRTQ_DESC_F_NEXT;
+        let mut idx = desc.next;
+
+        // カーネルはストレートマッピングなので、バッファのアドレスをそのまま渡せる
+        // (size_maxを超えるバッファは複数のディスクリプタに分ける)
+        for seg in segments {
+            let mut off = 0;
+            while off < seg.len {
+                let len = if config.size_max == 0 {
+                    seg.len
+                } else {
+                    (seg.len - off).min(config.size_max as usize)
+                };
+                let desc = &mut vq.desc[idx as usize];
+                desc.addr = seg.addr as u64 + off as u64;
+                desc.len = len as u32;
+                desc.flags = VIRTQ_DESC_F_NEXT | if device_writes { VIRTQ_DESC_F_WRITE } else { 0 };
+                idx = desc.next;
+                off += len;
+            }
+        }
+
+        if let Some(range) = range {
+            blk_req.range = range;
+            let desc = &mut vq.desc[idx as usize];
+            desc.addr = blk_req_paddr + mem::offset_of!(VirtioBlkReq, range) as u64;
+            desc.len = mem::size_of::<VirtioBlkDiscardWriteZeroes>() as u32;
+            desc.flags = VIRTQ_DESC_F_NEXT;
+            idx = desc.next;
+        }
 
-    // デバイスへの処理要求を格納する領域を確保
-    BLK_REQ_PADDR =
-        alloc_pages(align_up(mem::size_of::<VirtioBlkReq>() as u64, PAGE_SIZE) / PAGE_SIZE);
-    BLK_REQ = BLK_REQ_PADDR.as_u64() as *mut VirtioBlkReq;
+        let desc = &mut vq.desc[idx as usize];
+        desc.addr = blk_req_paddr + mem::offset_of!(VirtioBlkReq, status) as u64;
+        desc.len = mem::size_of::<u8>() as u32;
+        desc.flags = VIRTQ_DESC_F_WRITE;
+
+        // デバイスに新しいリクエストがあることを通知する
+        Virtq::kick(vq, head as u32);
+
+        if self.wait_for_completion(head).is_err() {
+            warn!("virtio: request timed out: type={type_}, sector={sector}, resetting device");
+            self.reset();
+        }
+
+        let status = blk_req.status;
+        vq.free_chain(head);
+        process::wakeup(self.free_desc_chan());
+
+        // 0でない値が帰ってきたらエラー
+        if status != 0 {
+            warn!("virtio: request failed: type={type_}, sector={sector}, status={status}");
+            return Err(());
+        }
+
+        Ok(())
+    }
 }
 
-pub unsafe fn read_write_disk(buf: *mut u8, sector: u32, is_write: bool) -> Result<(), ()> {
-    if sector >= BLK_CAPACITY / SECTOR_SIZE {
-        println!(
-            "virtio: tried to read/write sector={sector}, but capacity is {}",
-            BLK_CAPACITY / SECTOR_SIZE
-        );
-        return Err(());
+impl BlockDevice for VirtioBlk {
+    fn capacity_sectors(&self) -> u64 {
+        self.config.capacity
     }
 
-    // リクエストを構築する
-    let blk_req = BLK_REQ.as_mut().unwrap();
-    blk_req.sector = sector as u64;
-    blk_req.type_ = if is_write {
-        VIRTIO_BLK_T_OUT as u32
-    } else {
-        VIRTIO_BLK_T_IN as u32
-    };
-    if is_write {
-        ptr::copy(
-            buf,
-            &mut blk_req.data as *mut [u8] as *mut u8,
-            SECTOR_SIZE as usize,
-        );
-    }
-
-    // virtqueueのディスクリプタを構築する
-    let vq = BLK_REQUEST_VQ.as_mut().unwrap();
-    vq.desc[0].addr = BLK_REQ_PADDR.as_u64();
-    vq.desc[0].len = (mem::size_of::<u32>() * 2 + mem::size_of::<u64>()) as u32;
-    vq.desc[0].flags = VIRTQ_DESC_F_NEXT;
-    vq.desc[0].next = 1;
-
-    vq.desc[1].addr = BLK_REQ_PADDR.as_u64() + mem::offset_of!(VirtioBlkReq, data) as u64;
-    vq.desc[1].len = SECTOR_SIZE;
-    vq.desc[1].flags = VIRTQ_DESC_F_NEXT | if is_write { 0 } else { VIRTQ_DESC_F_WRITE };
-    vq.desc[1].next = 2;
-
-    vq.desc[2].addr = BLK_REQ_PADDR.as_u64() + mem::offset_of!(VirtioBlkReq, status) as u64;
-    vq.desc[2].len = mem::size_of::<u8>() as u32;
-    vq.desc[2].flags = VIRTQ_DESC_F_WRITE;
-
-    // デバイスに新しいリクエストがあることを通知する
-    Virtq::kick(vq, 0);
-
-    while vq.is_busy() {}
-
-    // 0でない値が帰ってきたらエラー
-    if blk_req.status != 0 {
-        println!(
-            "virtio: warn: failed to read/write sector={sector}, status={}",
-            blk_req.status
-        );
-        return Err(());
+    fn block_size(&self) -> u32 {
+        self.config.blk_size
     }
 
-    // 読み込み処理の場合は、バッファにデータをコピーする
-    if !is_write {
-        ptr::copy(
-            &blk_req.data as *const [u8] as *const u8,
-            buf,
-            SECTOR_SIZE as usize,
-        );
+    fn is_read_only(&self) -> bool {
+        self.config.read_only
     }
 
-    Ok(())
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> Result<(), ()> {
+        let segment = Segment {
+            addr: buf.as_mut_ptr(),
+            len: buf.len(),
+        };
+        unsafe { self.read_write_sg(&[segment], sector, false) }
+    }
+
+    fn write(&mut self, sector: u64, buf: &[u8]) -> Result<(), ()> {
+        // デバイスはバッファを読むだけ
+        let segment = Segment {
+            addr: buf.as_ptr() as *mut u8,
+            len: buf.len(),
+        };
+        unsafe { self.read_write_sg(&[segment], sector, true) }
+    }
+
+    fn flush(&mut self) -> Result<(), ()> {
+        // FLUSHに対応していないデバイスは書き込みが完了した時点で永続化されている
+        if self.features & VIRTIO_BLK_F_FLUSH == 0 {
+            return Ok(());
+        }
+        unsafe { self.submit(VIRTIO_BLK_T_FLUSH, 0, &[], None) }
+    }
+
+    fn discard(&mut self, sector: u64, count: u64) -> Result<(), ()> {
+        if self.features & VIRTIO_BLK_F_DISCARD == 0 {
+            return Err(());
+        }
+        let max = self.config.max_discard_sectors;
+        unsafe { self.submit_ranges(VIRTIO_BLK_T_DISCARD, sector, count, max, 0) }
+    }
+
+    /// WRITE_ZEROESに対応していないデバイスでは0のデータを書き込む
+    fn write_zeroes(&mut self, sector: u64, count: u64, unmap: bool) -> Result<(), ()> {
+        if self.features & VIRTIO_BLK_F_WRITE_ZEROES == 0 {
+            let zeroes = unsafe { &*ptr::addr_of!(ZEROES) };
+            let chunk = (ZEROES_SIZE / SECTOR_SIZE as usize) as u64;
+            let mut off = 0;
+            while off < count {
+                let n = (count - off).min(chunk);
+                self.write(sector + off, &zeroes[0..n as usize * SECTOR_SIZE as usize])?;
+                off += n;
+            }
+            return Ok(());
+        }
+
+        let flags = if unmap && self.config.write_zeroes_may_unmap {
+            VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP
+        } else {
+            0
+        };
+        let max = self.config.max_write_zeroes_sectors;
+        unsafe { self.submit_ranges(VIRTIO_BLK_T_WRITE_ZEROES, sector, count, max, flags) }
+    }
 }
@@ -0,0 +1,233 @@
+use crate::types::PhysAddr;
+
+pub const VIRTIO_MAGIC: u32 = 0x74726976;
+const VIRTIO_REG_MAGIC: u64 = 0x00;
+const VIRTIO_REG_VERSION: u64 = 0x04;
+const VIRTIO_REG_DEVICE_ID: u64 = 0x08;
+const VIRTIO_REG_DEVICE_FEATURES: u64 = 0x10;
+const VIRTIO_REG_DEVICE_FEATURES_SEL: u64 = 0x14;
+const VIRTIO_REG_DRIVER_FEATURES: u64 = 0x20;
+const VIRTIO_REG_DRIVER_FEATURES_SEL: u64 = 0x24;
+const VIRTIO_REG_QUEUE_SEL: u64 = 0x30;
+const VIRTIO_REG_QUEUE_NUM_MAX: u64 = 0x34;
+const VIRTIO_REG_QUEUE_NUM: u64 = 0x38;
+const VIRTIO_REG_QUEUE_ALIGN: u64 = 0x3c;
+const VIRTIO_REG_QUEUE_PFN: u64 = 0x40;
+const VIRTIO_REG_QUEUE_READY: u64 = 0x44;
+const VIRTIO_REG_QUEUE_NOTIFY: u64 = 0x50;
+const VIRTIO_REG_INTERRUPT_STATUS: u64 = 0x60;
+const VIRTIO_REG_INTERRUPT_ACK: u64 = 0x64;
+const VIRTIO_REG_DEVICE_STATUS: u64 = 0x70;
+const VIRTIO_REG_QUEUE_DESC_LOW: u64 = 0x80;
+const VIRTIO_REG_QUEUE_DESC_HIGH: u64 = 0x84;
+const VIRTIO_REG_QUEUE_DRIVER_LOW: u64 = 0x90;
+const VIRTIO_REG_QUEUE_DRIVER_HIGH: u64 = 0x94;
+const VIRTIO_REG_QUEUE_DEVICE_LOW: u64 = 0xa0;
+const VIRTIO_REG_QUEUE_DEVICE_HIGH: u64 = 0xa4;
+const VIRTIO_REG_CONFIG_GENERATION: u64 = 0xfc;
+const VIRTIO_REG_DEVICE_CONFIG: u64 = 0x100;
+pub const VIRTIO_STATUS_ACK: u32 = 1;
+pub const VIRTIO_STATUS_DRIVER: u32 = 2;
+pub const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+pub const VIRTIO_STATUS_FEAT_OK: u32 = 8;
+pub const VIRTIO_STATUS_FAILED: u32 = 128;
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// レガシー (バージョン1) のvirtio-mmio
+pub const VIRTIO_MMIO_VERSION_LEGACY: u32 = 1;
+/// Virtio 1.0以降 (バージョン2) のvirtio-mmio
+pub const VIRTIO_MMIO_VERSION_MODERN: u32 = 2;
+
+/// virtio-mmioのトランスポート層のレジスタ
+#[derive(Debug, Clone, Copy)]
+pub struct Transport {
+    base: PhysAddr,
+    version: u32,
+}
+
+impl Transport {
+    pub const fn new(base: PhysAddr) -> Self {
+        Self {
+            base,
+            version: VIRTIO_MMIO_VERSION_LEGACY,
+        }
+    }
+
+    /// `base` にvirtio-mmioデバイスがあれば、そのトランスポートを返す
+    pub unsafe fn probe(base: PhysAddr) -> Option<Self> {
+        let transport = Self { base, version: 0 };
+        if transport.read32(VIRTIO_REG_MAGIC) != VIRTIO_MAGIC {
+            return None;
+        }
+        let version = transport.read32(VIRTIO_REG_VERSION);
+        if version != VIRTIO_MMIO_VERSION_LEGACY && version != VIRTIO_MMIO_VERSION_MODERN {
+            return None;
+        }
+        Some(Self { base, version })
+    }
+
+    pub fn base(&self) -> PhysAddr {
+        self.base
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == VIRTIO_MMIO_VERSION_LEGACY
+    }
+
+    unsafe fn read32(&self, offset: u64) -> u32 {
+        ((self.base.as_u64() + offset) as *const u32).read_volatile()
+    }
+
+    unsafe fn write32(&self, offset: u64, value: u32) {
+        ((self.base.as_u64() + offset) as *mut u32).write_volatile(value);
+    }
+
+    /// デバイスの種類 (0ならデバイスが接続されていない)
+    pub unsafe fn device_id(&self) -> u32 {
+        self.read32(VIRTIO_REG_DEVICE_ID)
+    }
+
+    pub unsafe fn reset(&self) {
+        self.write32(VIRTIO_REG_DEVICE_STATUS, 0);
+    }
+
+    pub unsafe fn status(&self) -> u32 {
+        self.read32(VIRTIO_REG_DEVICE_STATUS)
+    }
+
+    pub unsafe fn add_status(&self, status: u32) {
+        self.write32(VIRTIO_REG_DEVICE_STATUS, self.status() | status);
+    }
+
+    /// デバイスが対応している機能ビット
+    unsafe fn device_features(&self) -> u64 {
+        self.write32(VIRTIO_REG_DEVICE_FEATURES_SEL, 0);
+        let low = self.read32(VIRTIO_REG_DEVICE_FEATURES) as u64;
+        if self.is_legacy() {
+            // レガシーデバイスは下位32ビットしか持たない
+            return low;
+        }
+        self.write32(VIRTIO_REG_DEVICE_FEATURES_SEL, 1);
+        let high = self.read32(VIRTIO_REG_DEVICE_FEATURES) as u64;
+        (high << 32) | low
+    }
+
+    /// ドライバが使う機能ビットをデバイスに伝える
+    unsafe fn set_driver_features(&self, features: u64) {
+        self.write32(VIRTIO_REG_DRIVER_FEATURES_SEL, 0);
+        self.write32(VIRTIO_REG_DRIVER_FEATURES, features as u32);
+        if !self.is_legacy() {
+            self.write32(VIRTIO_REG_DRIVER_FEATURES_SEL, 1);
+            self.write32(VIRTIO_REG_DRIVER_FEATURES, (features >> 32) as u32);
+        }
+    }
+
+    /// 機能ビットのネゴシエーションを行い、FEATURES_OKまで進める
+    ///
+    /// デバイスとドライバの両方が対応している機能ビットを返す
+    pub unsafe fn negotiate_features(&self, supported: u64) -> Result<u64, ()> {
+        // 4. Read device feature bits, and write the subset of feature bits understood by the OS
+        //    and driver to the device.
+        let device_features = self.device_features();
+        let mut features = device_features & supported;
+        if !self.is_legacy() {
+            // モダンデバイスはVIRTIO_F_VERSION_1をネゴシエートしないと使えない
+            if device_features & VIRTIO_F_VERSION_1 == 0 {
+                return Err(());
+            }
+            features |= VIRTIO_F_VERSION_1;
+        }
+        self.set_driver_features(features);
+
+        // 5. Set the FEATURES_OK status bit.
+        self.add_status(VIRTIO_STATUS_FEAT_OK);
+        // 6. Re-read device status to ensure the FEATURES_OK bit is still set: otherwise,
+        //    the device does not support our subset of features and the device is unusable.
+        if !self.is_legacy() && self.status() & VIRTIO_STATUS_FEAT_OK == 0 {
+            return Err(());
+        }
+
+        Ok(features)
+    }
+
+    /// virtqueueをデバイスに登録する
+    ///
+    /// レガシーデバイスでは `desc` から `avail` と `used` が決まったレイアウトで
+    /// 並んでいる必要がある
+    pub unsafe fn setup_queue(
+        &self,
+        index: u32,
+        num: u32,
+        desc: PhysAddr,
+        avail: PhysAddr,
+        used: PhysAddr,
+    ) -> Result<(), ()> {
+        // 1. Select the queue writing its index (first queue is 0) to QueueSel.
+        self.write32(VIRTIO_REG_QUEUE_SEL, index);
+        // 3. Read maximum queue size (number of elements) from QueueNumMax.
+        let num_max = self.read32(VIRTIO_REG_QUEUE_NUM_MAX);
+        if num_max == 0 || num > num_max {
+            return Err(());
+        }
+        // 5. Notify the device about the queue size by writing the size to QueueNum.
+        self.write32(VIRTIO_REG_QUEUE_NUM, num);
+
+        if self.is_legacy() {
+            // 6. Notify the device about the used alignment by writing its value in bytes to QueueAlign.
+            self.write32(VIRTIO_REG_QUEUE_ALIGN, 0);
+            // 7. Write the physical number of the first page of the queue to the QueuePFN register.
+            self.write32(VIRTIO_REG_QUEUE_PFN, desc.as_u64() as u32);
+        } else {
+            // 6. Write physical addresses of the queue's Descriptor Area, Driver Area and
+            //    Device Area to (respectively) the QueueDescLow/QueueDescHigh,
+            //    QueueDriverLow/QueueDriverHigh and QueueDeviceLow/QueueDeviceHigh register pairs.
+            self.write32(VIRTIO_REG_QUEUE_DESC_LOW, desc.as_u64() as u32);
+            self.write32(VIRTIO_REG_QUEUE_DESC_HIGH, (desc.as_u64() >> 32) as u32);
+            self.write32(VIRTIO_REG_QUEUE_DRIVER_LOW, avail.as_u64() as u32);
+            self.write32(VIRTIO_REG_QUEUE_DRIVER_HIGH, (avail.as_u64() >> 32) as u32);
+            self.write32(VIRTIO_REG_QUEUE_DEVICE_LOW, used.as_u64() as u32);
+            self.write32(VIRTIO_REG_QUEUE_DEVICE_HIGH, (used.as_u64() >> 32) as u32);
+            // 7. Write 0x1 to QueueReady.
+            self.write32(VIRTIO_REG_QUEUE_READY, 1);
+        }
+
+        Ok(())
+    }
+
+    pub unsafe fn notify(&self, index: u32) {
+        self.write32(VIRTIO_REG_QUEUE_NOTIFY, index);
+    }
+
+    /// 割り込みの要因を読み取って応答する
+    pub unsafe fn ack_interrupt(&self) -> u32 {
+        let status = self.read32(VIRTIO_REG_INTERRUPT_STATUS);
+        self.write32(VIRTIO_REG_INTERRUPT_ACK, status);
+        status
+    }
+
+    pub unsafe fn config_read8(&self, offset: u64) -> u8 {
+        ((self.base.as_u64() + VIRTIO_REG_DEVICE_CONFIG + offset) as *const u8).read_volatile()
+    }
+
+    pub unsafe fn config_read32(&self, offset: u64) -> u32 {
+        self.read32(VIRTIO_REG_DEVICE_CONFIG + offset)
+    }
+
+    /// デバイス固有の設定領域から64ビットの値を読む
+    pub unsafe fn config_read64(&self, offset: u64) -> u64 {
+        if self.is_legacy() {
+            return ((self.base.as_u64() + VIRTIO_REG_DEVICE_CONFIG + offset) as *const u64)
+                .read_volatile();
+        }
+
+        // 読んでいる途中で設定が変わっていないか世代番号で確かめる
+        loop {
+            let generation = self.read32(VIRTIO_REG_CONFIG_GENERATION);
+            let low = self.config_read32(offset) as u64;
+            let high = self.config_read32(offset + 4) as u64;
+            if generation == self.read32(VIRTIO_REG_CONFIG_GENERATION) {
+                return (high << 32) | low;
+            }
+        }
+    }
+}